@@ -15,6 +15,60 @@ fn bench_cpu_distance(b: &mut Bencher) {
     b.iter(move || normalized_cosine_distance_scalar(&e1, &e2));
 }
 
+#[bench]
+fn bench_default_distance(b: &mut Bencher) {
+    let seed: u64 = 42;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let e1 = random_normalized_embedding(&mut rng);
+    let e2 = random_normalized_embedding(&mut rng);
+
+    b.iter(move || normalized_cosine_distance(&e1, &e2));
+}
+
+#[bench]
+fn bench_cpu_normalize(b: &mut Bencher) {
+    let seed: u64 = 42;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let e = random_embedding(&mut rng);
+
+    b.iter(move || {
+        let mut e = e;
+        normalize_vec_scalar(&mut e);
+        e
+    });
+}
+
+#[bench]
+fn bench_binarize(b: &mut Bencher) {
+    let seed: u64 = 42;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let e = random_normalized_embedding(&mut rng);
+
+    b.iter(move || binarize(&e));
+}
+
+#[bench]
+fn bench_hamming_distance(b: &mut Bencher) {
+    let seed: u64 = 42;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let b1 = binarize(&random_normalized_embedding(&mut rng));
+    let b2 = binarize(&random_normalized_embedding(&mut rng));
+
+    b.iter(move || hamming_distance(&b1, &b2));
+}
+
+#[bench]
+fn bench_distance_matrix_32(b: &mut Bencher) {
+    let seed: u64 = 42;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let embeddings: Vec<Embedding> = (0..32)
+        .map(|_| random_normalized_embedding(&mut rng))
+        .collect();
+    let refs: Vec<&Embedding> = embeddings.iter().collect();
+
+    b.iter(move || distance_matrix(&refs));
+}
+
 #[cfg(feature = "simd")]
 mod simd_benches {
     use rand::{rngs::StdRng, SeedableRng};
@@ -41,4 +95,17 @@ mod simd_benches {
 
         b.iter(move || normalized_cosine_distance_simd_unaligned(&e1, &e2));
     }
+
+    #[bench]
+    fn bench_simd_normalize(b: &mut Bencher) {
+        let seed: u64 = 42;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let e = random_embedding(&mut rng);
+
+        b.iter(move || {
+            let mut e = e;
+            normalize_vec_simd_unaligned(&mut e);
+            e
+        });
+    }
 }
@@ -0,0 +1,54 @@
+#![feature(test)]
+extern crate test;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use test::Bencher;
+
+use terminusdb_semantic_indexer::indexer::TopK;
+
+fn random_distances(n: usize) -> Vec<u32> {
+    let seed: u64 = 42;
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..n).map(|_| rng.gen()).collect()
+}
+
+fn sort_and_truncate(distances: &[u32], k: usize) -> Vec<(u32, usize)> {
+    let mut all: Vec<(u32, usize)> = distances.iter().copied().zip(0..).collect();
+    all.sort();
+    all.truncate(k);
+    all
+}
+
+fn top_k(distances: &[u32], k: usize) -> Vec<(u32, usize)> {
+    let mut top = TopK::new(k);
+    for (i, &distance) in distances.iter().enumerate() {
+        top.push((distance, i), ());
+    }
+    top.into_sorted_vec()
+        .into_iter()
+        .map(|(key, ())| key)
+        .collect()
+}
+
+#[bench]
+fn bench_sort_and_truncate_10_of_100k(b: &mut Bencher) {
+    let distances = random_distances(100_000);
+    b.iter(|| sort_and_truncate(&distances, 10));
+}
+
+#[bench]
+fn bench_top_k_10_of_100k(b: &mut Bencher) {
+    let distances = random_distances(100_000);
+    b.iter(|| top_k(&distances, 10));
+}
+
+#[bench]
+fn bench_sort_and_truncate_1000_of_100k(b: &mut Bencher) {
+    let distances = random_distances(100_000);
+    b.iter(|| sort_and_truncate(&distances, 1000));
+}
+
+#[bench]
+fn bench_top_k_1000_of_100k(b: &mut Bencher) {
+    let distances = random_distances(100_000);
+    b.iter(|| top_k(&distances, 1000));
+}
@@ -0,0 +1,208 @@
+#![allow(unused, dead_code)]
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::openai::Model;
+
+const API_KEY_HEADER: &str = "VECTORLINK_EMBEDDING_API_KEY";
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("error while doing request: {0:?}")]
+    ReqwestError(#[from] reqwest::Error),
+    #[error("invalid server url: {0}")]
+    BadUrl(#[from] url::ParseError),
+    #[error("server responded with status code {0}: {1}")]
+    BadStatus(StatusCode, String),
+    #[error("unexpected response from server: {0}")]
+    BadResponse(#[from] serde_json::Error),
+    #[error("indexing failed: {0}")]
+    TaskFailed(String),
+    #[error("no such task: {0}")]
+    UnknownTask(String),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SearchResult {
+    pub id: String,
+    pub distance: f32,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "status")]
+pub enum TaskState {
+    Pending { percentage: f32 },
+    Complete { indexed_documents: usize },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmbedResult {
+    pub model: Model,
+    pub embedding: Vec<f32>,
+}
+
+/// A client for the HTTP API of a semantic indexer server.
+pub struct Client {
+    base_url: Url,
+    api_key: Option<String>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// Create a client for the server at `base_url`, passing
+    /// `api_key` to the embedding provider where a request needs it.
+    pub fn new(base_url: &str, api_key: Option<String>) -> Result<Self, ClientError> {
+        // endpoint paths are joined onto the base url, which only
+        // keeps its last path segment if it ends in a slash.
+        let mut base_url = Url::parse(base_url)?;
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        Ok(Self {
+            base_url,
+            api_key,
+            http: reqwest::Client::new(),
+        })
+    }
+
+    fn url(&self, path: &str, params: &[(&str, &str)]) -> Result<Url, ClientError> {
+        let mut url = self.base_url.join(path)?;
+        url.query_pairs_mut().extend_pairs(params);
+        Ok(url)
+    }
+
+    fn with_api_key(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.api_key {
+            Some(api_key) => request.header(API_KEY_HEADER, api_key),
+            None => request,
+        }
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<String, ClientError> {
+        let response = self.with_api_key(request).send().await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(ClientError::BadStatus(status, body))
+        }
+    }
+
+    pub async fn search(
+        &self,
+        domain: &str,
+        commit: &str,
+        query: &str,
+        count: usize,
+    ) -> Result<Vec<SearchResult>, ClientError> {
+        let count = count.to_string();
+        let url = self.url(
+            "v1/search",
+            &[("domain", domain), ("commit", commit), ("count", &count)],
+        )?;
+        let body = self
+            .send(self.http.post(url).body(query.to_string()))
+            .await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Start indexing `commit` of `domain`, returning the task id.
+    pub async fn start_index(
+        &self,
+        domain: &str,
+        commit: &str,
+        previous: Option<&str>,
+    ) -> Result<String, ClientError> {
+        let mut params = vec![("domain", domain), ("commit", commit)];
+        if let Some(previous) = previous {
+            params.push(("previous", previous));
+        }
+        let url = self.url("v1/index", &params)?;
+        self.send(self.http.get(url)).await
+    }
+
+    /// Get the state of a task. With `wait`, the server holds the
+    /// request until the task's state changes or a timeout passes.
+    pub async fn task_state(&self, task_id: &str, wait: bool) -> Result<TaskState, ClientError> {
+        let mut params = vec![("task_id", task_id)];
+        if wait {
+            params.push(("wait", "true"));
+        }
+        let url = self.url("v1/check", &params)?;
+        match self.send(self.http.get(url)).await {
+            Ok(body) => Ok(serde_json::from_str(&body)?),
+            Err(ClientError::BadStatus(StatusCode::INTERNAL_SERVER_ERROR, message)) => {
+                Err(ClientError::TaskFailed(message))
+            }
+            Err(ClientError::BadStatus(StatusCode::NOT_FOUND, _)) => {
+                Err(ClientError::UnknownTask(task_id.to_string()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wait until a task completes, returning the number of indexed
+    /// documents.
+    pub async fn wait_for_task(&self, task_id: &str) -> Result<usize, ClientError> {
+        loop {
+            if let TaskState::Complete { indexed_documents } =
+                self.task_state(task_id, true).await?
+            {
+                return Ok(indexed_documents);
+            }
+        }
+    }
+
+    /// Embed `text`, using the model of `domain` if one is given.
+    pub async fn embed(
+        &self,
+        text: &str,
+        domain: Option<&str>,
+    ) -> Result<EmbedResult, ClientError> {
+        let params: Vec<_> = domain
+            .map(|domain| ("domain", domain))
+            .into_iter()
+            .collect();
+        let url = self.url("v1/embed", &params)?;
+        let body = self
+            .send(self.http.post(url).body(text.to_string()))
+            .await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_joined_onto_base_path() {
+        let client = Client::new("http://localhost:8080/vectorlink", None).unwrap();
+        let url = client
+            .url("v1/search", &[("domain", "admin/star_wars")])
+            .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://localhost:8080/vectorlink/v1/search?domain=admin%2Fstar_wars"
+        );
+    }
+
+    #[test]
+    fn parse_task_states() {
+        let pending: TaskState =
+            serde_json::from_str(r#"{"status":"Pending","percentage":0.3}"#).unwrap();
+        assert_eq!(pending, TaskState::Pending { percentage: 0.3 });
+        let complete: TaskState = serde_json::from_str(
+            r#"{"status":"Complete","indexed_documents":12,"rejected_embeddings":0}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            complete,
+            TaskState::Complete {
+                indexed_documents: 12
+            }
+        );
+    }
+}
@@ -1,19 +1,25 @@
 #![allow(unused, dead_code)]
 use crate::{
-    openai::{embeddings_for, EmbeddingError},
+    log::log,
+    openai::{EmbeddingCache, EmbeddingError, EmbeddingProvider, EmbeddingUsage, Model},
     server::Operation,
-    vecmath::{self, Embedding},
-    vectors::{Domain, LoadedVec, VectorStore},
+    vecmath::{self, BinaryEmbedding, Embedding, EmbeddingProblem},
+    vectors::{Domain, Embedder, LoadedVec, ModelMismatchError, Provenance, VectorStore},
 };
+use clap::ValueEnum;
 use hnsw::{Hnsw, Searcher};
+use rand::{Rng, SeedableRng};
 use rand_pcg::Lcg128Xsl64;
-use serde::{Deserialize, Serialize};
+use serde::{ser::Error as _, Deserialize, Serialize, Serializer};
 use space::{Metric, Neighbor};
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::{
-    io,
+    io::{self, Write},
     iter::{self, zip},
-    path::PathBuf,
+    ops::Range,
+    path::{Path, PathBuf},
 };
 use thiserror::Error;
 use urlencoding::{decode, encode};
@@ -33,6 +39,21 @@ pub struct IndexPoint {
     index: usize,
 }
 
+// A stored point is serialized as the `IndexPoint` it is read back as,
+// so that an index can be written without converting a copy of it.
+impl Serialize for Point {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Point::Stored { id, vec } => IndexPoint {
+                id: id.clone(),
+                index: vec.id(),
+            }
+            .serialize(serializer),
+            Point::Mem { .. } => Err(S::Error::custom("can not serialize a memory point")),
+        }
+    }
+}
+
 impl Point {
     pub fn id(&self) -> &str {
         match self {
@@ -41,14 +62,14 @@ impl Point {
         }
     }
 
-    fn vec_id(&self) -> usize {
+    pub fn vec_id(&self) -> usize {
         match self {
             Point::Stored { id, vec } => vec.id(),
             Point::Mem { vec } => panic!("You can not get the vector id of a memory point"),
         }
     }
 
-    fn vec(&self) -> &Embedding {
+    pub fn vec(&self) -> &Embedding {
         match self {
             Point::Stored { id: _, vec } => vec,
             Point::Mem { vec } => vec,
@@ -59,9 +80,15 @@ impl Point {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct OpenAI;
 
+thread_local! {
+    // Distances computed on this thread, for search statistics.
+    static DISTANCE_COMPUTATIONS: Cell<u64> = Cell::new(0);
+}
+
 impl Metric<Point> for OpenAI {
     type Unit = u32;
     fn distance(&self, p1: &Point, p2: &Point) -> u32 {
+        DISTANCE_COMPUTATIONS.with(|c| c.set(c.get() + 1));
         let a = p1.vec();
         let b = p2.vec();
         let f = vecmath::normalized_cosine_distance(a, b);
@@ -88,58 +115,138 @@ enum Op {
     Changed,
 }
 
+/// What to do with an embedding that contains NaN or infinite values,
+/// or is the zero vector.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum InvalidEmbeddingPolicy {
+    /// Abort indexing.
+    #[default]
+    Fail,
+    /// Leave the operation out of the index.
+    Skip,
+    /// Zero out invalid values and renormalize.
+    Replace,
+}
+
+/// An operation left out of the index because its embedding was invalid.
+#[derive(Debug)]
+pub struct RejectedEmbedding {
+    pub id: String,
+    pub problem: EmbeddingProblem,
+}
+
 pub async fn operations_to_point_operations(
     domain: &Domain,
     vector_store: &VectorStore,
     structs: Vec<Result<Operation, std::io::Error>>,
     key: &str,
-) -> Result<Vec<PointOperation>, IndexError> {
+    model: Model,
+    embedding_cache: &EmbeddingCache,
+    policy: InvalidEmbeddingPolicy,
+) -> Result<(Vec<PointOperation>, Vec<RejectedEmbedding>, EmbeddingUsage), IndexError> {
     // Should not unwrap here -
     let ops: Vec<Operation> = structs.into_iter().collect::<Result<Vec<_>, _>>()?;
-    let tuples: Vec<(Op, String, String)> = ops
+    let tuples: Vec<(usize, Op, String, String)> = ops
         .iter()
-        .flat_map(|o| match o {
-            Operation::Inserted { string, id } => Some((Op::Insert, string.into(), id.into())),
-            Operation::Changed { string, id } => Some((Op::Changed, string.into(), id.into())),
+        .enumerate()
+        .flat_map(|(position, o)| match o {
+            Operation::Inserted { string, id } => {
+                Some((position, Op::Insert, string.into(), id.into()))
+            }
+            Operation::Changed { string, id } => {
+                Some((position, Op::Changed, string.into(), id.into()))
+            }
             Operation::Deleted { id: _ } => None,
             Operation::Error { message } => {
-                eprintln!("{}", message);
+                log!("{message}");
                 None
             }
         })
         .collect();
-    let strings: Vec<String> = tuples.iter().map(|(_, s, _)| s.to_string()).collect();
-    let vecs: Vec<Embedding> = if strings.is_empty() {
-        Vec::new()
+    let strings: Vec<String> = tuples
+        .iter()
+        .map(|(_, _, s, _)| model.document_text(s))
+        .collect();
+    let (vecs, usage) = if strings.is_empty() {
+        (Vec::new(), EmbeddingUsage::default())
     } else {
-        embeddings_for(key, &strings).await?
+        let embeddings = embedding_cache.embeddings_for(key, &strings, model).await?;
+        (embeddings.embeddings, embeddings.usage)
     };
+    domain.record_embedding_tokens(usage.prompt_tokens);
+    let mut rejected = Vec::new();
+    let (tuples, vecs): (Vec<_>, Vec<_>) = zip(tuples, vecs)
+        .filter_map(|(tuple, mut vec)| {
+            if let Some(problem) = vecmath::embedding_problem(&vec) {
+                match policy {
+                    InvalidEmbeddingPolicy::Fail => {
+                        return Some(Err(IndexError::InvalidEmbedding {
+                            id: tuple.3,
+                            problem,
+                        }))
+                    }
+                    InvalidEmbeddingPolicy::Skip => {
+                        rejected.push(RejectedEmbedding {
+                            id: tuple.3,
+                            problem,
+                        });
+                        return None;
+                    }
+                    InvalidEmbeddingPolicy::Replace => vecmath::repair_embedding(&mut vec),
+                }
+            }
+            Some(Ok((tuple, vec)))
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .unzip();
     let loaded_vecs: Vec<LoadedVec> = vector_store.add_and_load_vecs(&domain, vecs.iter())?;
-    let mut new_ops: Vec<PointOperation> = zip(tuples, loaded_vecs)
-        .map(|((op, _, id), vec)| match op {
-            Op::Insert => PointOperation::Insert {
-                point: Point::Stored { vec, id },
-            },
-            Op::Changed => PointOperation::Replace {
-                point: Point::Stored { vec, id },
-            },
+    let mut new_ops: Vec<(usize, PointOperation)> = zip(tuples, loaded_vecs)
+        .map(|((position, op, _, id), vec)| {
+            let operation = match op {
+                Op::Insert => PointOperation::Insert {
+                    point: Point::Stored { vec, id },
+                },
+                Op::Changed => PointOperation::Replace {
+                    point: Point::Stored { vec, id },
+                },
+            };
+            (position, operation)
         })
         .collect();
-    let mut delete_ops: Vec<_> = ops
+    new_ops.extend(
+        ops.into_iter()
+            .enumerate()
+            .flat_map(|(position, o)| match o {
+                Operation::Deleted { id } => Some((position, PointOperation::Delete { id })),
+                _ => None,
+            }),
+    );
+    // Keep the operations in the order they came in, so that a delete
+    // only removes the points inserted before it.
+    new_ops.sort_by_key(|(position, _)| *position);
+    let new_ops = new_ops
         .into_iter()
-        .flat_map(|o| match o {
-            Operation::Deleted { id } => Some(PointOperation::Delete { id }),
-            _ => None,
-        })
+        .map(|(_, operation)| operation)
         .collect();
-    new_ops.append(&mut delete_ops);
-    Ok(new_ops)
+    Ok((new_ops, rejected, usage))
+}
+
+/// An operation that could not be indexed, along with the line it
+/// came from and the reason it failed.
+#[derive(Serialize, Deserialize)]
+pub struct QuarantineRecord {
+    pub line: usize,
+    pub error: String,
+    pub operation: String,
 }
 
 pub struct IndexIdentifier {
     pub previous: Option<String>,
     pub commit: String,
     pub domain: String,
+    /// Seed for a new index, if there is no previous one.
+    pub seed: Option<u64>,
 }
 
 #[derive(Debug, Error)]
@@ -150,12 +257,152 @@ pub enum IndexError {
     IoError(#[from] std::io::Error),
     #[error("Embedding error: {0:?}")]
     EmbeddingError(#[from] EmbeddingError),
+    #[error("{0}")]
+    ModelMismatch(#[from] ModelMismatchError),
+    #[error("Invalid embedding for {id}: {problem}")]
+    InvalidEmbedding {
+        id: String,
+        problem: EmbeddingProblem,
+    },
+}
+
+/// Resolve the model to index `domain` with, recording it along with
+/// `provider` as the domain's model if the domain did not have one yet.
+pub fn model_for_indexing(
+    domain: &Domain,
+    requested: Option<Model>,
+    provider: &EmbeddingProvider,
+    allow_mismatch: bool,
+) -> Result<Model, IndexError> {
+    let model = domain.resolve_model(requested, provider, allow_mismatch)?;
+    if domain.model().is_none() {
+        domain.set_model(model, Embedder::new(provider, model))?;
+    }
+    Ok(model)
 }
 
 /*
 pub fn serialize_index(domain: Domain, hnsw: HnswIndex) -> io::Result<()> {}
  */
 
+/// Create an empty index. The seed determines which layers inserted
+/// points are assigned to, so that builds over the same operations
+/// produce the same graph; without one a fixed default seed is used.
+pub fn new_index(seed: Option<u64>) -> HnswIndex {
+    match seed {
+        Some(seed) => Hnsw::new_prng(OpenAI, Lcg128Xsl64::seed_from_u64(seed)),
+        None => Hnsw::new(OpenAI),
+    }
+}
+
+/// The points of an index whose documents were deleted or replaced,
+/// by internal id. They stay in the graph, which keeps it connected,
+/// but are left out of search results.
+///
+/// Tombstones belong to an index rather than to its domain: the
+/// indexes of different commits share the vectors of the domain, and a
+/// document deleted in one commit is still there in the ones before.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tombstones {
+    bitmap: Vec<u8>,
+    // The number of bits set in `bitmap`.
+    count: usize,
+}
+
+impl Tombstones {
+    fn from_bitmap(bitmap: Vec<u8>) -> Self {
+        let count = bitmap.iter().map(|byte| byte.count_ones() as usize).sum();
+        Tombstones { bitmap, count }
+    }
+
+    pub fn mark(&mut self, index: usize) {
+        if self.bitmap.len() <= index / 8 {
+            self.bitmap.resize(index / 8 + 1, 0);
+        }
+        let bit = 1 << (index % 8);
+        if self.bitmap[index / 8] & bit == 0 {
+            self.bitmap[index / 8] |= bit;
+            self.count += 1;
+        }
+    }
+
+    pub fn is_deleted(&self, index: usize) -> bool {
+        self.bitmap
+            .get(index / 8)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+/// The internal ids of the points of an index by external id. An id
+/// has several points once its document was replaced.
+#[derive(Clone, Debug, Default)]
+pub struct IdIndex(HashMap<String, Vec<usize>>);
+
+impl IdIndex {
+    pub fn new(hnsw: &HnswIndex) -> Self {
+        let mut ids = IdIndex::default();
+        for index in 0..hnsw.layer_len(0) {
+            ids.insert(hnsw.feature(index).id(), index);
+        }
+        ids
+    }
+
+    fn insert(&mut self, id: &str, index: usize) {
+        self.0.entry(id.to_string()).or_default().push(index);
+    }
+
+    pub fn points(&self, id: &str) -> &[usize] {
+        self.0.get(id).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Apply operations to an index in the order they are given. Inserted
+/// and replacing points are added to the graph, while the points of
+/// replaced or deleted documents are marked in `tombstones`, so a
+/// document that is inserted and then deleted by the same operations
+/// ends up deleted. `ids` is kept up to date with the inserted points.
+/// Returns the number of points marked.
+pub fn apply_operations(
+    hnsw: &mut HnswIndex,
+    tombstones: &mut Tombstones,
+    ids: &mut IdIndex,
+    operations: Vec<PointOperation>,
+) -> usize {
+    let mut searcher = Searcher::default();
+    let marked_before = tombstones.len();
+    for operation in operations {
+        let point = match operation {
+            PointOperation::Insert { point } => point,
+            PointOperation::Replace { point } => {
+                for &index in ids.points(point.id()) {
+                    tombstones.mark(index);
+                }
+                point
+            }
+            PointOperation::Delete { id } => {
+                for &index in ids.points(&id) {
+                    tombstones.mark(index);
+                }
+                continue;
+            }
+        };
+        ids.insert(point.id(), hnsw.layer_len(0));
+        hnsw.insert(point, &mut searcher);
+    }
+    tombstones.len() - marked_before
+}
+
+/// Build up an index from operations that only add points. Deletes
+/// are ignored; operations that delete points go through
+/// `apply_operations` instead.
 pub fn start_indexing_from_operations(
     mut hnsw: HnswIndex,
     operations: Vec<PointOperation>,
@@ -163,11 +410,10 @@ pub fn start_indexing_from_operations(
     let mut searcher = Searcher::default();
     for operation in operations {
         match operation {
-            PointOperation::Insert { point } => {
+            PointOperation::Insert { point } | PointOperation::Replace { point } => {
                 hnsw.insert(point.clone(), &mut searcher);
             }
-            PointOperation::Replace { point: _ } => todo!(),
-            PointOperation::Delete { id: _ } => todo!(),
+            PointOperation::Delete { id: _ } => {}
         }
     }
     // Put this index somewhere!
@@ -197,12 +443,35 @@ impl PointQuery {
         self.point.id()
     }
 
+    /// The id of the result's vector within its domain.
+    pub fn vec_id(&self) -> usize {
+        self.point.vec_id()
+    }
+
     pub fn distance(&self) -> u32 {
         self.distance
     }
 }
 
-pub fn search(p: &Point, mut num: usize, hnsw: &HnswIndex) -> Result<Vec<PointQuery>, SearchError> {
+/// Statistics about the traversal done by a single search.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SearchStatistics {
+    pub distance_computations: u64,
+    /// The number of layers in the searched index, all of which a
+    /// graph search descends through.
+    pub index_layers: usize,
+    pub ef: usize,
+}
+
+pub fn search(p: &Point, num: usize, hnsw: &HnswIndex) -> Result<Vec<PointQuery>, SearchError> {
+    search_with_statistics(p, num, hnsw).map(|(points, _)| points)
+}
+
+pub fn search_with_statistics(
+    p: &Point,
+    mut num: usize,
+    hnsw: &HnswIndex,
+) -> Result<(Vec<PointQuery>, SearchStatistics), SearchError> {
     // We need to set the number correctly
     // to make sure we don't go out of bounds
     let layer_len = hnsw.layer_len(0);
@@ -217,7 +486,13 @@ pub fn search(p: &Point, mut num: usize, hnsw: &HnswIndex) -> Result<Vec<PointQu
     .collect();
     let mut searcher = Searcher::default();
     let ef = num.max(100);
+    let computations_before = DISTANCE_COMPUTATIONS.with(Cell::get);
     hnsw.nearest(p, ef, &mut searcher, &mut output);
+    let statistics = SearchStatistics {
+        distance_computations: DISTANCE_COMPUTATIONS.with(Cell::get) - computations_before,
+        index_layers: hnsw.layers(),
+        ef,
+    };
     let mut points = Vec::with_capacity(num);
     for elt in output {
         points.push(PointQuery {
@@ -226,20 +501,1061 @@ pub fn search(p: &Point, mut num: usize, hnsw: &HnswIndex) -> Result<Vec<PointQu
             distance: elt.distance,
         })
     }
-    Ok(points)
+    Ok((points, statistics))
+}
+
+struct TopKEntry<K, T> {
+    key: K,
+    item: T,
+}
+
+impl<K: Ord, T> PartialEq for TopKEntry<K, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<K: Ord, T> Eq for TopKEntry<K, T> {}
+
+impl<K: Ord, T> PartialOrd for TopKEntry<K, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord, T> Ord for TopKEntry<K, T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// Collects the `k` items with the smallest keys out of any number
+/// pushed, in a max-heap of at most `k` entries, so the rest are
+/// neither kept nor sorted.
+pub struct TopK<K, T> {
+    k: usize,
+    heap: BinaryHeap<TopKEntry<K, T>>,
+}
+
+impl<K: Ord, T> TopK<K, T> {
+    pub fn new(k: usize) -> Self {
+        // `k` can be far more than is ever pushed, so the heap only
+        // grows with what it holds.
+        Self {
+            k,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub fn push(&mut self, key: K, item: T) {
+        if self.heap.len() < self.k {
+            self.heap.push(TopKEntry { key, item });
+        } else if let Some(mut largest) = self.heap.peek_mut() {
+            if key < largest.key {
+                *largest = TopKEntry { key, item };
+            }
+        }
+    }
+
+    /// The collected items, smallest key first.
+    pub fn into_sorted_vec(self) -> Vec<(K, T)> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|entry| (entry.key, entry.item))
+            .collect()
+    }
+}
+
+/// Restricts the documents a search may return by their external id.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum IdFilter {
+    Allow(BTreeSet<String>),
+    Deny(BTreeSet<String>),
+}
+
+impl IdFilter {
+    pub fn admits(&self, id: &str) -> bool {
+        match self {
+            IdFilter::Allow(ids) => ids.contains(id),
+            IdFilter::Deny(ids) => !ids.contains(id),
+        }
+    }
+}
+
+/// Search for the `num` nearest points admitted by `filter` that were
+/// not deleted.
+///
+/// Filtering the results of an ordinary search would lose every
+/// admitted point that did not make it into them. Instead, the points
+/// of an allowlist are looked up in `ids` and compared with the query
+/// one by one, which finds exactly the nearest of them. With a
+/// denylist the graph search is widened until it has found `num`
+/// admitted points or has considered the whole index.
+pub fn search_filtered(
+    p: &Point,
+    num: usize,
+    hnsw: &HnswIndex,
+    ids: &IdIndex,
+    filter: &IdFilter,
+    tombstones: &Tombstones,
+) -> Result<(Vec<PointQuery>, SearchStatistics), SearchError> {
+    match filter {
+        IdFilter::Allow(allowed) => {
+            let computations_before = DISTANCE_COMPUTATIONS.with(Cell::get);
+            let mut nearest = TopK::new(num);
+            let mut candidates = 0;
+            for index in allowed
+                .iter()
+                .flat_map(|id| ids.points(id))
+                .filter(|&&index| !tombstones.is_deleted(index))
+            {
+                candidates += 1;
+                // Equally distant points are ordered by index.
+                nearest.push((OpenAI.distance(p, hnsw.feature(*index)), *index), ());
+            }
+            let points = nearest
+                .into_sorted_vec()
+                .into_iter()
+                .map(|((distance, index), ())| PointQuery {
+                    id: index,
+                    point: hnsw.feature(index).clone(),
+                    distance,
+                })
+                .collect();
+            let statistics = SearchStatistics {
+                distance_computations: DISTANCE_COMPUTATIONS.with(Cell::get) - computations_before,
+                index_layers: hnsw.layers(),
+                ef: candidates,
+            };
+            Ok((points, statistics))
+        }
+        IdFilter::Deny(ids) => search_widening(p, num, num + ids.len(), hnsw, |point| {
+            filter.admits(point.id()) && !tombstones.is_deleted(point.internal_id())
+        }),
+    }
+}
+
+/// Search for the `num` nearest points that were not deleted.
+pub fn search_live(
+    p: &Point,
+    num: usize,
+    hnsw: &HnswIndex,
+    tombstones: &Tombstones,
+) -> Result<Vec<PointQuery>, SearchError> {
+    search_live_with_statistics(p, num, hnsw, tombstones).map(|(points, _)| points)
+}
+
+/// Search for the `num` nearest points that were not deleted. Any
+/// number of the nearest points may be deleted, so the search is
+/// widened until enough are found.
+pub fn search_live_with_statistics(
+    p: &Point,
+    num: usize,
+    hnsw: &HnswIndex,
+    tombstones: &Tombstones,
+) -> Result<(Vec<PointQuery>, SearchStatistics), SearchError> {
+    let candidates = num + tombstones.len().min(num);
+    search_widening(p, num, candidates, hnsw, |point| {
+        !tombstones.is_deleted(point.internal_id())
+    })
+}
+
+/// Search for `candidates` points, doubling that until `num` of them
+/// are admitted or the whole index has been considered, and return the
+/// `num` nearest admitted ones.
+fn search_widening(
+    p: &Point,
+    num: usize,
+    mut candidates: usize,
+    hnsw: &HnswIndex,
+    admits: impl Fn(&PointQuery) -> bool,
+) -> Result<(Vec<PointQuery>, SearchStatistics), SearchError> {
+    let layer_len = hnsw.layer_len(0);
+    let mut distance_computations = 0;
+    loop {
+        let (mut points, statistics) = search_with_statistics(p, candidates, hnsw)?;
+        distance_computations += statistics.distance_computations;
+        points.retain(&admits);
+        if points.len() >= num || candidates >= layer_len {
+            points.truncate(num);
+            return Ok((
+                points,
+                SearchStatistics {
+                    distance_computations,
+                    ..statistics
+                },
+            ));
+        }
+        candidates *= 2;
+    }
+}
+
+/// Search a domain for the `num` nearest vectors by first taking the
+/// `num * rerank` closest vectors of its binary quantized copy by
+/// Hamming distance, then ordering those by their exact distance.
+/// Returns the vector ids along with their distances.
+pub fn search_binarized(
+    query: &Embedding,
+    num: usize,
+    rerank: usize,
+    bits: &[BinaryEmbedding],
+    store: &VectorStore,
+    domain: &Domain,
+) -> io::Result<Vec<(usize, f32)>> {
+    let binary_query = vecmath::binarize(query);
+    let mut candidates = TopK::new(num.saturating_mul(rerank.max(1)));
+    for (index, vec_bits) in bits.iter().enumerate() {
+        // Equally distant vectors are ordered by index.
+        candidates.push(
+            (vecmath::hamming_distance(&binary_query, vec_bits), index),
+            (),
+        );
+    }
+    let mut nearest = TopK::new(num);
+    for ((_, index), ()) in candidates.into_sorted_vec() {
+        let vec = store.get_vec(domain, index)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("binarized vector {index} is not in the domain"),
+            )
+        })?;
+        let distance = vecmath::normalized_cosine_distance(query, &vec);
+        nearest.push((distance.to_bits(), index), ());
+    }
+    Ok(nearest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|((distance, index), ())| (index, f32::from_bits(distance)))
+        .collect())
+}
+
+/// Search for several queries at once, spreading them over all cores,
+/// for points that were not deleted. Results are returned in the order
+/// of the queries.
+pub fn search_batch(
+    queries: &[&Point],
+    num: usize,
+    hnsw: &HnswIndex,
+    tombstones: &Tombstones,
+) -> Result<Vec<Vec<PointQuery>>, SearchError> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = queries.len().div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = queries
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|query| search_live(query, num, hnsw, tombstones))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+            })
+            .collect();
+        let mut results = Vec::with_capacity(queries.len());
+        for handle in handles {
+            results.extend(handle.join().unwrap()?);
+        }
+        Ok(results)
+    })
+}
+
+/// Find the most recently inserted point with the given external id
+/// that was not deleted.
+pub fn find_point<'a>(hnsw: &'a HnswIndex, tombstones: &Tombstones, id: &str) -> Option<&'a Point> {
+    (0..hnsw.layer_len(0))
+        .rev()
+        .filter(|&i| !tombstones.is_deleted(i))
+        .map(|i| hnsw.feature(i))
+        .find(|p| p.id() == id)
+}
+
+/// The internal ids of the points that were not deleted, in insertion
+/// order.
+fn live_points(hnsw: &HnswIndex, tombstones: &Tombstones) -> Vec<usize> {
+    (0..hnsw.layer_len(0))
+        .filter(|&i| !tombstones.is_deleted(i))
+        .collect()
+}
+
+/// Iterate over the points at `range` positions in the index's
+/// bottom layer, which holds every point in insertion order, yielding
+/// external ids with their vectors. Deleted points are skipped.
+pub fn points_in_range<'a>(
+    hnsw: &'a HnswIndex,
+    tombstones: &'a Tombstones,
+    range: Range<usize>,
+) -> impl Iterator<Item = (&'a str, &'a Embedding)> {
+    let end = range.end.min(hnsw.layer_len(0));
+    (range.start.min(end)..end)
+        .filter(|&i| !tombstones.is_deleted(i))
+        .map(move |i| {
+            let point = hnsw.feature(i);
+            (point.id(), point.vec())
+        })
+}
+
+/// Pick `count` points that were not deleted from the index uniformly
+/// at random, in insertion order.
+pub fn sample_points<'a, R: Rng>(
+    hnsw: &'a HnswIndex,
+    tombstones: &Tombstones,
+    count: usize,
+    rng: &mut R,
+) -> impl Iterator<Item = (&'a str, &'a Embedding)> {
+    let live = live_points(hnsw, tombstones);
+    let mut indexes: Vec<usize> = rand::seq::index::sample(rng, live.len(), count.min(live.len()))
+        .into_iter()
+        .map(|i| live[i])
+        .collect();
+    indexes.sort_unstable();
+    indexes.into_iter().map(move |i| {
+        let point = hnsw.feature(i);
+        (point.id(), point.vec())
+    })
+}
+
+/// Copy the vectors of an index over `source` into two new domains,
+/// depending on whether their external id is in `ids`, and build an
+/// index over each part. Deleted points are left out, and the others
+/// keep their provenance.
+pub fn split_index(
+    hnsw: &HnswIndex,
+    tombstones: &Tombstones,
+    vector_store: &VectorStore,
+    source: &Domain,
+    ids: &HashSet<String>,
+    matching: &Domain,
+    rest: &Domain,
+) -> io::Result<(HnswIndex, HnswIndex)> {
+    // The store hands out one domain per name.
+    if std::ptr::eq(matching, rest) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can not split into a single domain",
+        ));
+    }
+    let points: Vec<&Point> = (0..hnsw.layer_len(0))
+        .filter(|&i| !tombstones.is_deleted(i))
+        .map(|i| hnsw.feature(i))
+        .collect();
+    let vec_ids: Vec<usize> = points.iter().map(|p| p.vec_id()).collect();
+    let provenance = source.provenance(&vec_ids)?;
+    let mut matching_ops = Vec::new();
+    let mut matching_provenance = Vec::new();
+    let mut rest_ops = Vec::new();
+    let mut rest_provenance = Vec::new();
+    for point in points {
+        let (domain, ops, records) = if ids.contains(point.id()) {
+            (matching, &mut matching_ops, &mut matching_provenance)
+        } else {
+            (rest, &mut rest_ops, &mut rest_provenance)
+        };
+        let vec = vector_store.add_and_load_vec(domain, point.vec())?;
+        if let Some(provenance) = provenance.get(&point.vec_id()) {
+            records.push((vec.id(), provenance.clone()));
+        }
+        ops.push(PointOperation::Insert {
+            point: Point::Stored {
+                id: point.id().to_string(),
+                vec,
+            },
+        });
+    }
+    matching.record_provenance(matching_provenance)?;
+    rest.record_provenance(rest_provenance)?;
+    Ok((
+        start_indexing_from_operations(Hnsw::new(OpenAI), matching_ops)?,
+        start_indexing_from_operations(Hnsw::new(OpenAI), rest_ops)?,
+    ))
+}
+
+/// An order of the points of an index that keeps neighbours close
+/// together, found by a breadth-first walk from each point to its
+/// `neighbours` nearest points. Returns internal ids.
+pub fn locality_order(hnsw: &HnswIndex, neighbours: usize) -> Vec<usize> {
+    let len = hnsw.layer_len(0);
+    let mut visited = vec![false; len];
+    let mut order = Vec::with_capacity(len);
+    let mut queue = VecDeque::new();
+    for start in 0..len {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        queue.push_back(start);
+        while let Some(i) = queue.pop_front() {
+            order.push(i);
+            for result in search(hnsw.feature(i), neighbours + 1, hnsw).unwrap() {
+                let j = result.internal_id();
+                if !visited[j] {
+                    visited[j] = true;
+                    queue.push_back(j);
+                }
+            }
+        }
+    }
+    order
+}
+
+/// Copy the vectors of an index into `target` in the given order of
+/// internal ids, and move the points of the index to the copies. The
+/// graph itself is left as it is, so the tombstones of the index still
+/// apply. Provenance is copied along with the vectors.
+pub fn reorder_index(
+    hnsw: HnswIndex,
+    vector_store: &VectorStore,
+    source: &Domain,
+    target: &Domain,
+    order: &[usize],
+) -> io::Result<HnswIndex> {
+    const CHUNK_SIZE: usize = 1024;
+    let mut vec_ids = HashMap::with_capacity(order.len());
+    for chunk in order.chunks(CHUNK_SIZE) {
+        let vecs = chunk.iter().map(|&i| hnsw.feature(i).vec());
+        let new_ids = vector_store.add_vecs(target, vecs)?;
+        for (&i, new_id) in chunk.iter().zip(new_ids) {
+            vec_ids.insert(hnsw.feature(i).vec_id(), new_id);
+        }
+    }
+
+    let old_ids: Vec<usize> = vec_ids.keys().copied().collect();
+    let provenance = source
+        .provenance(&old_ids)?
+        .into_iter()
+        .map(|(old_id, provenance)| (vec_ids[&old_id], provenance))
+        .collect();
+    target.record_provenance(provenance)?;
+
+    // Loaded up front, as the transform below can not fail.
+    let mut loaded = HashMap::with_capacity(vec_ids.len());
+    for (&old_id, &new_id) in &vec_ids {
+        let vec = vector_store.get_vec(target, new_id)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("copied vector {new_id} is missing"),
+            )
+        })?;
+        loaded.insert(old_id, vec);
+    }
+    Ok(hnsw.transform_features(|point| Point::Stored {
+        id: point.id().to_string(),
+        vec: loaded[&point.vec_id()].clone(),
+    }))
+}
+
+/// Pair every point with its `neighbours` nearest other points,
+/// returning internal ids and distances. Each pair is returned once.
+/// Deleted points are left out.
+pub fn neighbour_pairs(
+    hnsw: &HnswIndex,
+    tombstones: &Tombstones,
+    neighbours: usize,
+) -> Vec<(usize, usize, f32)> {
+    // Searched in blocks to bound the memory held by results.
+    const BLOCK_SIZE: usize = 4096;
+    let mut seen = HashSet::new();
+    let mut pairs = Vec::new();
+    let live = live_points(hnsw, tombstones);
+    for block in live.chunks(BLOCK_SIZE) {
+        let queries: Vec<&Point> = block.iter().map(|&i| hnsw.feature(i)).collect();
+        let results = search_batch(&queries, neighbours + 1, hnsw, tombstones).unwrap();
+        for (&i, results) in block.iter().zip(results) {
+            for result in results {
+                let j = result.internal_id();
+                if i != j && seen.insert((i.min(j), i.max(j))) {
+                    pairs.push((i.min(j), i.max(j), f32::from_bits(result.distance())));
+                }
+            }
+        }
+    }
+    pairs
+}
+
+/// A pair of close points from two different indexes, each given by
+/// the position of its index and its internal id in that index.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CrossIndexPair {
+    pub a: (usize, usize),
+    pub b: (usize, usize),
+    pub distance: f32,
+}
+
+/// Find the points of different indexes that are closer than
+/// `threshold`. Every index is searched with the points of each index
+/// after it, in blocks, for their `neighbours` nearest points. Deleted
+/// points are left out on both sides.
+pub fn cross_index_pairs(
+    indexes: &[(&HnswIndex, &Tombstones)],
+    neighbours: usize,
+    threshold: f32,
+) -> Vec<CrossIndexPair> {
+    // Searched in blocks to bound the memory held by results.
+    const BLOCK_SIZE: usize = 4096;
+    let mut pairs = Vec::new();
+    for (a, (source, source_tombstones)) in indexes.iter().enumerate() {
+        for (b, (target, target_tombstones)) in indexes.iter().enumerate().skip(a + 1) {
+            let len = source.layer_len(0);
+            for start in (0..len).step_by(BLOCK_SIZE) {
+                let live: Vec<usize> = (start..len.min(start + BLOCK_SIZE))
+                    .filter(|&i| !source_tombstones.is_deleted(i))
+                    .collect();
+                let queries: Vec<&Point> = live.iter().map(|&i| source.feature(i)).collect();
+                let results =
+                    search_batch(&queries, neighbours, target, target_tombstones).unwrap();
+                for (&i, results) in live.iter().zip(results) {
+                    for result in results {
+                        let distance = f32::from_bits(result.distance());
+                        if distance < threshold {
+                            pairs.push(CrossIndexPair {
+                                a: (a, i),
+                                b: (b, result.internal_id()),
+                                distance,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+    pairs
+}
+
+/// A group of documents connected through duplicate pairs.
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicateCluster {
+    pub size: usize,
+    pub sample_ids: Vec<String>,
+    pub mean_distance: f32,
+}
+
+/// Summary of the clusters formed by a set of duplicate pairs.
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicateReport {
+    pub clusters: usize,
+    pub duplicated_documents: usize,
+    /// Number of clusters of each size.
+    pub size_histogram: BTreeMap<usize, usize>,
+    pub largest: Vec<DuplicateCluster>,
+}
+
+// Members of a cluster used to estimate its mean internal distance.
+const DUPLICATE_CLUSTER_SAMPLE: usize = 100;
+const DUPLICATE_CLUSTER_SAMPLE_IDS: usize = 5;
+
+fn union_find_root(parents: &mut HashMap<usize, usize>, mut i: usize) -> usize {
+    while let Some(&parent) = parents.get(&i) {
+        if parent == i {
+            break;
+        }
+        let grandparent = parents[&parent];
+        parents.insert(i, grandparent);
+        i = parent;
+    }
+    i
+}
+
+/// Group duplicate pairs of internal ids into clusters, reporting the
+/// `largest` biggest ones in detail.
+pub fn duplicate_report(
+    hnsw: &HnswIndex,
+    pairs: &[(usize, usize)],
+    largest: usize,
+) -> DuplicateReport {
+    duplicate_report_by(|i| hnsw.feature(i), pairs, largest)
+}
+
+/// Like `duplicate_report`, for pairs of ids that `feature` resolves
+/// to points, which may come from several indexes.
+pub fn duplicate_report_by<'a, F: Fn(usize) -> &'a Point>(
+    feature: F,
+    pairs: &[(usize, usize)],
+    largest: usize,
+) -> DuplicateReport {
+    let mut parents: HashMap<usize, usize> = HashMap::new();
+    for &(i, j) in pairs {
+        parents.entry(i).or_insert(i);
+        parents.entry(j).or_insert(j);
+        let root_i = union_find_root(&mut parents, i);
+        let root_j = union_find_root(&mut parents, j);
+        if root_i != root_j {
+            parents.insert(root_i.max(root_j), root_i.min(root_j));
+        }
+    }
+    let mut clusters: HashMap<usize, Vec<usize>> = HashMap::new();
+    let members: Vec<usize> = parents.keys().copied().collect();
+    for i in members {
+        let root = union_find_root(&mut parents, i);
+        clusters.entry(root).or_default().push(i);
+    }
+    let mut clusters: Vec<Vec<usize>> = clusters.into_values().collect();
+    for cluster in clusters.iter_mut() {
+        cluster.sort_unstable();
+    }
+    clusters.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+
+    let mut size_histogram = BTreeMap::new();
+    for cluster in clusters.iter() {
+        *size_histogram.entry(cluster.len()).or_insert(0) += 1;
+    }
+    let largest = clusters
+        .iter()
+        .take(largest)
+        .map(|cluster| {
+            let sample = &cluster[..cluster.len().min(DUPLICATE_CLUSTER_SAMPLE)];
+            let mut total = 0.0;
+            let mut count = 0;
+            for (n, &i) in sample.iter().enumerate() {
+                for &j in &sample[n + 1..] {
+                    total += f32::from_bits(OpenAI.distance(feature(i), feature(j)));
+                    count += 1;
+                }
+            }
+            DuplicateCluster {
+                size: cluster.len(),
+                sample_ids: cluster
+                    .iter()
+                    .take(DUPLICATE_CLUSTER_SAMPLE_IDS)
+                    .map(|&i| feature(i).id().to_string())
+                    .collect(),
+                mean_distance: if count == 0 {
+                    0.0
+                } else {
+                    total / count as f32
+                },
+            }
+        })
+        .collect();
+
+    DuplicateReport {
+        clusters: clusters.len(),
+        duplicated_documents: clusters.iter().map(Vec::len).sum(),
+        size_histogram,
+        largest,
+    }
+}
+
+/// Distances of `samples` random pairs of points and of `samples`
+/// random points to their nearest neighbour, each sorted ascending.
+/// Deleted points are left out.
+pub fn calibration_distances<R: Rng>(
+    hnsw: &HnswIndex,
+    tombstones: &Tombstones,
+    samples: usize,
+    rng: &mut R,
+) -> (Vec<f32>, Vec<f32>) {
+    let live = live_points(hnsw, tombstones);
+    let len = live.len();
+    if len < 2 {
+        return (Vec::new(), Vec::new());
+    }
+    let mut random = Vec::with_capacity(samples);
+    while random.len() < samples {
+        let (i, j) = (live[rng.gen_range(0..len)], live[rng.gen_range(0..len)]);
+        if i != j {
+            let distance = OpenAI.distance(hnsw.feature(i), hnsw.feature(j));
+            random.push(f32::from_bits(distance));
+        }
+    }
+    let mut near: Vec<f32> = rand::seq::index::sample(rng, len, samples.min(len))
+        .into_iter()
+        .map(|i| live[i])
+        .filter_map(|i| {
+            search_live(hnsw.feature(i), 2, hnsw, tombstones)
+                .unwrap()
+                .into_iter()
+                .find(|result| result.internal_id() != i)
+                .map(|result| f32::from_bits(result.distance()))
+        })
+        .collect();
+    random.sort_by(f32::total_cmp);
+    near.sort_by(f32::total_cmp);
+    (random, near)
+}
+
+/// Estimate the recall of searches for `k` nearest points, by searching
+/// for `samples` random points of the index and comparing the results
+/// with their exact nearest points, found by comparing with every
+/// point. A result counts as found if it is no further away than the
+/// `k`th exact nearest point, so that ties do not count against the
+/// search. Returns `None` for an empty index.
+pub fn estimate_recall<R: Rng>(
+    hnsw: &HnswIndex,
+    k: usize,
+    samples: usize,
+    rng: &mut R,
+) -> Option<f32> {
+    let len = hnsw.layer_len(0);
+    let k = k.min(len);
+    if k == 0 {
+        return None;
+    }
+    let mut found = 0;
+    let mut wanted = 0;
+    for i in rand::seq::index::sample(rng, len, samples.min(len)) {
+        let query = hnsw.feature(i);
+        let mut exact = TopK::new(k);
+        for j in 0..len {
+            exact.push(OpenAI.distance(query, hnsw.feature(j)), ());
+        }
+        let furthest = exact.into_sorted_vec().last().unwrap().0;
+        found += search(query, k, hnsw)
+            .unwrap()
+            .iter()
+            .filter(|result| result.distance() <= furthest)
+            .count();
+        wanted += k;
+    }
+    Some(found as f32 / wanted as f32)
+}
+
+/// The value below which `p` percent of the ascending `sorted` values
+/// lie, using the nearest rank.
+pub fn percentile(sorted: &[f32], p: f32) -> Option<f32> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f32).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// How well a duplicate threshold separates labeled pairs.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ThresholdQuality {
+    pub threshold: f32,
+    pub precision: f32,
+    pub recall: f32,
+}
+
+/// Precision and recall of treating pairs closer than each threshold
+/// as duplicates, given pairs as (distance, is duplicate).
+pub fn threshold_quality(pairs: &[(f32, bool)], thresholds: &[f32]) -> Vec<ThresholdQuality> {
+    let duplicates = pairs.iter().filter(|(_, duplicate)| *duplicate).count();
+    thresholds
+        .iter()
+        .map(|&threshold| {
+            let (mut true_positives, mut positives) = (0, 0);
+            for &(distance, duplicate) in pairs {
+                if distance < threshold {
+                    positives += 1;
+                    true_positives += duplicate as usize;
+                }
+            }
+            let ratio = |n: usize, d: usize| if d == 0 { 1.0 } else { n as f32 / d as f32 };
+            ThresholdQuality {
+                threshold,
+                precision: ratio(true_positives, positives),
+                recall: ratio(true_positives, duplicates),
+            }
+        })
+        .collect()
+}
+
+/// The provenance of the vectors added for `point_operations`, given
+/// the operations they were made from and the line of each operation.
+pub fn provenance_for(
+    commit: &str,
+    operations: &[(usize, Operation)],
+    point_operations: &[PointOperation],
+) -> Vec<(usize, Provenance)> {
+    // An id can occur more than once, so the operations of each id are
+    // matched with its points in order.
+    let mut sources: HashMap<&str, VecDeque<(usize, &str)>> = HashMap::new();
+    for (line, operation) in operations {
+        if let Operation::Inserted { string, id } | Operation::Changed { string, id } = operation {
+            sources
+                .entry(id.as_str())
+                .or_default()
+                .push_back((*line, string.as_str()));
+        }
+    }
+    point_operations
+        .iter()
+        .filter_map(|operation| match operation {
+            PointOperation::Insert { point } | PointOperation::Replace { point } => {
+                let (line, string) = sources.get_mut(point.id())?.pop_front()?;
+                Some((
+                    point.vec_id(),
+                    Provenance {
+                        commit: commit.to_string(),
+                        line,
+                        content_hash: stable_hash(string.as_bytes()),
+                    },
+                ))
+            }
+            PointOperation::Delete { .. } => None,
+        })
+        .collect()
+}
+
+/// Remove results further away than `max_distance`, if given.
+pub fn within_distance(mut points: Vec<PointQuery>, max_distance: Option<f32>) -> Vec<PointQuery> {
+    if let Some(max_distance) = max_distance {
+        points.retain(|p| f32::from_bits(p.distance) <= max_distance);
+    }
+    points
+}
+
+/// Remove results whose external id was already returned with a
+/// smaller distance.
+pub fn dedup_by_id(mut points: Vec<PointQuery>) -> Vec<PointQuery> {
+    points.sort_by_key(|p| p.distance);
+    let mut seen = HashSet::new();
+    points
+        .into_iter()
+        .filter(|p| seen.insert(p.id().to_string()))
+        .collect()
+}
+
+/// Facts about a serialized index, stored in a small `.meta` file
+/// next to it so they can be read without deserializing the index.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexMetadata {
+    pub domain: String,
+    pub commit: String,
+    pub vector_count: usize,
+    /// The number of points in each layer, starting from the bottom.
+    pub layer_sizes: Vec<usize>,
+    /// Checksum of the serialized index file.
+    #[serde(default)]
+    pub checksum: Option<u64>,
+    /// The generation of the index file that is current. Indexes
+    /// serialized before generations were numbered have generation 0.
+    #[serde(default)]
+    pub generation: u64,
+    /// The generation that was current before, which is kept to fall
+    /// back to if the current one turns out to be damaged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<IndexGeneration>,
+}
+
+/// A generation of an index file along with its checksum.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexGeneration {
+    pub generation: u64,
+    pub checksum: Option<u64>,
+}
+
+impl IndexMetadata {
+    fn of(name: &str, hnsw: &HnswIndex) -> Self {
+        let (domain, commit) = parse_index_name(name);
+        IndexMetadata {
+            domain,
+            commit,
+            vector_count: hnsw.layer_len(0),
+            layer_sizes: (0..hnsw.layers()).map(|l| hnsw.layer_len(l)).collect(),
+            checksum: None,
+            generation: 0,
+            previous: None,
+        }
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a checksum of `bytes`, continuing from `hash`. Unlike
+/// `DefaultHasher` its output does not change between Rust versions.
+fn checksum(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// A hash of `bytes` that is stable across builds, for hashes that are
+/// persisted.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    checksum(FNV_OFFSET_BASIS, bytes)
+}
+
+/// A writer computing the checksum of everything written through it.
+struct ChecksumWriter<W> {
+    inner: W,
+    checksum: u64,
+}
+
+impl<W: io::Write> io::Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.checksum = checksum(self.checksum, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader computing the checksum of everything read through it.
+struct ChecksumReader<R> {
+    inner: R,
+    checksum: u64,
+}
+
+impl<R: io::Read> io::Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.checksum = checksum(self.checksum, &buf[..read]);
+        Ok(read)
+    }
+}
+
+/// The path a file is written to before being renamed into place.
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    temporary.into()
+}
+
+/// Flush the entries of a directory, so that files renamed into it
+/// are still there after a crash.
+fn sync_directory(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The name of the file holding a generation of an index (with
+/// extension `hnsw`) or of its tombstones (`deleted`).
+fn generation_file_name(name: &str, generation: u64, extension: &str) -> String {
+    if generation == 0 {
+        format!("{name}.{extension}")
+    } else {
+        format!("{name}.{generation}.{extension}")
+    }
 }
 
-pub fn serialize_index(mut path: PathBuf, name: &str, hnsw: HnswIndex) -> io::Result<()> {
-    //let name = encode(name);
-    path.push(format!("{name}.hnsw"));
-    let write_file = File::options().write(true).create(true).open(&path)?;
+fn read_tombstones(path: &Path) -> io::Result<Tombstones> {
+    match std::fs::read(path) {
+        Ok(bitmap) => Ok(Tombstones::from_bitmap(bitmap)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Tombstones::default()),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn read_index_metadata(mut path: PathBuf, name: &str) -> io::Result<IndexMetadata> {
+    path.push(format!("{name}.meta"));
+    let read_file = File::open(&path)?;
+    serde_json::from_reader(read_file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Read the metadata of every index in the directory, or only those of
+/// `domain`. Indexes serialized without metadata are left out.
+pub fn list_index_metadata(path: PathBuf, domain: Option<&str>) -> io::Result<Vec<IndexMetadata>> {
+    let mut metadata = Vec::new();
+    for entry in std::fs::read_dir(&path)? {
+        let file_name = entry?.file_name();
+        // Stray files that are not named like an index are skipped.
+        let Some((name, (index_domain, _))) = file_name
+            .to_str()
+            .and_then(|n| n.strip_suffix(".meta"))
+            .and_then(|n| Some((n, try_parse_index_name(n)?)))
+        else {
+            continue;
+        };
+        if domain.is_some_and(|domain| index_domain != domain) {
+            continue;
+        }
+        metadata.push(read_index_metadata(path.clone(), name)?);
+    }
+    metadata.sort_by(|a, b| (&a.domain, &a.commit).cmp(&(&b.domain, &b.commit)));
+    Ok(metadata)
+}
 
-    let hnsw = hnsw.transform_features(|t| IndexPoint {
-        id: t.id().to_string(),
-        index: t.vec_id(),
+/// Serialize the index to disk, returning the size of the written file.
+///
+/// Every serialization writes a new generation of the index to a file
+/// of its own, reads it back to verify its checksum, and then renames a
+/// new `.meta` file into place that names this generation and records
+/// its checksum. Readers find the index through the metadata, so until
+/// the rename they read the previous generation, which an interrupted
+/// write leaves intact. The tombstones of the index are written to a
+/// file of the generation as well, so they are published along with
+/// it. The previous generation is kept for readers to fall back to,
+/// and the one before it is removed.
+pub fn serialize_index(
+    path: PathBuf,
+    name: &str,
+    hnsw: &HnswIndex,
+    tombstones: &Tombstones,
+) -> io::Result<u64> {
+    let previous = read_index_metadata(path.clone(), name).ok();
+    let mut metadata = IndexMetadata::of(name, hnsw);
+    metadata.generation = previous.as_ref().map_or(0, |m| m.generation) + 1;
+    metadata.previous = previous.as_ref().map(|m| IndexGeneration {
+        generation: m.generation,
+        checksum: m.checksum,
     });
-    serde_json::to_writer(write_file, &hnsw)?;
-    Ok(())
+    let index_path = path.join(generation_file_name(name, metadata.generation, "hnsw"));
+    let meta_path = path.join(format!("{name}.meta"));
+
+    let mut writer = ChecksumWriter {
+        inner: io::BufWriter::new(File::create(&index_path)?),
+        checksum: FNV_OFFSET_BASIS,
+    };
+    serde_json::to_writer(&mut writer, hnsw)?;
+    metadata.checksum = Some(writer.checksum);
+    let write_file = writer.inner.into_inner().map_err(|e| e.into_error())?;
+    write_file.sync_all()?;
+    let size = write_file.metadata()?.len();
+    if file_checksum(&index_path)? != writer.checksum {
+        remove_file_if_exists(&index_path)?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("index {name} does not read back as written"),
+        ));
+    }
+
+    let mut tombstones_file =
+        File::create(path.join(generation_file_name(name, metadata.generation, "deleted")))?;
+    tombstones_file.write_all(&tombstones.bitmap)?;
+    tombstones_file.sync_all()?;
+
+    let meta_file = File::create(temporary_path(&meta_path))?;
+    serde_json::to_writer(&meta_file, &metadata)?;
+    meta_file.sync_all()?;
+    std::fs::rename(temporary_path(&meta_path), &meta_path)?;
+    sync_directory(&path)?;
+
+    if let Some(stale) = previous.and_then(|m| m.previous) {
+        for extension in ["hnsw", "deleted"] {
+            remove_file_if_exists(&path.join(generation_file_name(
+                name,
+                stale.generation,
+                extension,
+            )))?;
+        }
+    }
+    Ok(size)
+}
+
+/// The checksum of the file at `path`, read in a streaming fashion.
+fn file_checksum(path: &Path) -> io::Result<u64> {
+    let mut reader = ChecksumReader {
+        inner: io::BufReader::new(File::open(path)?),
+        checksum: FNV_OFFSET_BASIS,
+    };
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.checksum)
+}
+
+/// Remove a serialized index along with its metadata.
+fn remove_index(path: &Path, name: &str) -> io::Result<()> {
+    let metadata = read_index_metadata(path.to_path_buf(), name).ok();
+    let mut generations = vec![metadata.as_ref().map_or(0, |m| m.generation)];
+    generations.extend(metadata.and_then(|m| m.previous).map(|p| p.generation));
+    for generation in generations {
+        for extension in ["hnsw", "deleted"] {
+            remove_file_if_exists(&path.join(generation_file_name(name, generation, extension)))?;
+        }
+    }
+    remove_file_if_exists(&path.join(format!("{name}.meta")))
 }
 
 pub fn create_index_name(domain: &str, commit: &str) -> String {
@@ -248,9 +1564,14 @@ pub fn create_index_name(domain: &str, commit: &str) -> String {
 }
 
 pub fn parse_index_name(name: &str) -> (String, String) {
-    let (domain, commit) = name.split_once('@').unwrap();
-    let domain = decode(domain).unwrap();
-    (domain.to_string(), commit.to_string())
+    try_parse_index_name(name).unwrap()
+}
+
+/// Split an index name into its domain and commit, if it is one.
+pub fn try_parse_index_name(name: &str) -> Option<(String, String)> {
+    let (domain, commit) = name.split_once('@')?;
+    let domain = decode(domain).ok()?;
+    Some((domain.to_string(), commit.to_string()))
 }
 
 pub fn deserialize_index(
@@ -258,16 +1579,152 @@ pub fn deserialize_index(
     name: &str,
     vector_store: &VectorStore,
 ) -> io::Result<HnswIndex> {
-    path.push(format!("{name}.hnsw"));
+    deserialize_index_with_tombstones(path, name, vector_store).map(|(hnsw, _)| hnsw)
+}
+
+/// Deserialize an index along with the tombstones of its deleted
+/// points. If the current generation of the index is damaged, the
+/// previous one is read instead.
+pub fn deserialize_index_with_tombstones(
+    path: &mut PathBuf,
+    name: &str,
+    vector_store: &VectorStore,
+) -> io::Result<(HnswIndex, Tombstones)> {
+    let metadata = match read_index_metadata(path.clone(), name) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let current = IndexGeneration {
+        generation: metadata.as_ref().map_or(0, |metadata| metadata.generation),
+        checksum: metadata.as_ref().and_then(|metadata| metadata.checksum),
+    };
+    match deserialize_generation(path, name, &current, vector_store) {
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            let Some(previous) = metadata.and_then(|metadata| metadata.previous) else {
+                return Err(e);
+            };
+            log!(
+                "{e}, reading generation {} of it instead",
+                previous.generation
+            );
+            deserialize_generation(path, name, &previous, vector_store)
+        }
+        result => result,
+    }
+}
+
+fn deserialize_generation(
+    path: &Path,
+    name: &str,
+    generation: &IndexGeneration,
+    vector_store: &VectorStore,
+) -> io::Result<(HnswIndex, Tombstones)> {
+    let tombstones =
+        read_tombstones(&path.join(generation_file_name(name, generation.generation, "deleted")))?;
+    let index_path = path.join(generation_file_name(name, generation.generation, "hnsw"));
     let (domain, _) = parse_index_name(name);
-    let read_file = File::options().read(true).open(&path)?;
-    let hnsw: HnswStorageIndex = serde_json::from_reader(read_file).unwrap();
+    let mut reader = io::BufReader::new(ChecksumReader {
+        inner: File::open(&index_path)?,
+        checksum: FNV_OFFSET_BASIS,
+    });
+    let hnsw: serde_json::Result<HnswStorageIndex> = serde_json::from_reader(&mut reader);
+    // A torn file fails to parse part way, so read the rest of it to
+    // report that it does not match its checksum.
+    io::copy(&mut reader, &mut io::sink())?;
+    let actual = reader.into_inner().checksum;
+    if let Some(expected) = generation.checksum {
+        if actual != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("index {name} does not match its checksum"),
+            ));
+        }
+    }
+    let hnsw = hnsw.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
     let domain = vector_store.get_domain(&domain)?;
     let hnsw = hnsw.transform_features(|t| Point::Stored {
         id: t.id,
         vec: vector_store.get_vec(&domain, t.index).unwrap().unwrap(),
     });
-    Ok(hnsw)
+    Ok((hnsw, tombstones))
+}
+
+/// How far an interrupted build got: the number of operation lines
+/// applied to the partial index saved with it.
+///
+/// The domain's vectors are shared with the indexes of other commits,
+/// so those added after the checkpoint are left in place and the
+/// resumed build adds its own again. Only the quarantine file belongs
+/// to the build alone, and is cut back on resume.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildCheckpoint {
+    pub lines: usize,
+    /// The length of the quarantine file, if the build writes one.
+    pub quarantine_length: u64,
+    /// The checksum of the partial index this checkpoint belongs to.
+    pub checksum: Option<u64>,
+}
+
+/// The directory partial indexes are kept in while they are built.
+pub fn staging_path(path: &Path) -> PathBuf {
+    path.join(".staging")
+}
+
+/// Save a partial index along with the number of operation lines
+/// applied to it, so that an interrupted build can be resumed.
+pub fn write_checkpoint(
+    path: &Path,
+    name: &str,
+    hnsw: &HnswIndex,
+    tombstones: &Tombstones,
+    lines: usize,
+    quarantine_length: u64,
+) -> io::Result<()> {
+    let staging = staging_path(path);
+    std::fs::create_dir_all(&staging)?;
+    serialize_index(staging.clone(), name, hnsw, tombstones)?;
+    let checkpoint = BuildCheckpoint {
+        lines,
+        quarantine_length,
+        checksum: read_index_metadata(staging.clone(), name)?.checksum,
+    };
+    let checkpoint_path = staging.join(format!("{name}.checkpoint"));
+    let checkpoint_file = File::create(temporary_path(&checkpoint_path))?;
+    serde_json::to_writer(&checkpoint_file, &checkpoint)?;
+    checkpoint_file.sync_all()?;
+    std::fs::rename(temporary_path(&checkpoint_path), &checkpoint_path)
+}
+
+/// Load the partial index of an interrupted build and its checkpoint,
+/// if there is one. A build interrupted while saving a checkpoint can
+/// leave an index that does not belong to the checkpoint, which is
+/// ignored.
+pub fn read_checkpoint(
+    path: &Path,
+    name: &str,
+    vector_store: &VectorStore,
+) -> io::Result<Option<(HnswIndex, Tombstones, BuildCheckpoint)>> {
+    let staging = staging_path(path);
+    let checkpoint: BuildCheckpoint = match File::open(staging.join(format!("{name}.checkpoint"))) {
+        Ok(file) => serde_json::from_reader(file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if read_index_metadata(staging.clone(), name)?.checksum != checkpoint.checksum {
+        return Ok(None);
+    }
+    let (hnsw, tombstones) =
+        deserialize_index_with_tombstones(&mut staging.clone(), name, vector_store)?;
+    Ok(Some((hnsw, tombstones, checkpoint)))
+}
+
+/// Remove the partial index and checkpoint of a finished build.
+pub fn remove_checkpoint(path: &Path, name: &str) -> io::Result<()> {
+    let staging = staging_path(path);
+    remove_file_if_exists(&staging.join(format!("{name}.checkpoint")))?;
+    remove_index(&staging, name)
 }
 
 #[cfg(test)]
@@ -276,6 +1733,37 @@ mod tests {
 
     use super::*;
 
+    /// Insert operations for points named `{prefix}{i}` for every `i`
+    /// in `dimensions`, with a vector along dimension `i` that is
+    /// tilted by `tilt` towards the next dimension.
+    fn test_operations(
+        store: &VectorStore,
+        domain: &Domain,
+        prefix: &str,
+        dimensions: Range<usize>,
+        tilt: f32,
+    ) -> Vec<PointOperation> {
+        dimensions
+            .map(|i| {
+                let mut e = [0.0; 1536];
+                e[i] = 1.0;
+                e[i + 1] = tilt;
+                PointOperation::Insert {
+                    point: Point::Stored {
+                        id: format!("{prefix}{i}"),
+                        vec: store.add_and_load_vec(domain, &e).unwrap(),
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// An index over `count` such points, named `doc0` and onwards.
+    fn test_index(store: &VectorStore, domain: &Domain, count: usize, tilt: f32) -> HnswIndex {
+        let operations = test_operations(store, domain, "doc", 0..count, tilt);
+        start_indexing_from_operations(Hnsw::new(OpenAI), operations).unwrap()
+    }
+
     #[test]
     fn low_dimensional_search() {
         let tempdir = tempfile::tempdir().unwrap();
@@ -339,4 +1827,589 @@ mod tests {
         assert_eq!(*p1.point.vec(), *e1);
         assert_eq!(*p2.point.vec(), *e2);
     }
+
+    #[test]
+    fn dedup_keeps_closest_result_per_id() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+        let domain = store.get_domain("foo").unwrap();
+        let [e1, e2] = store
+            .add_and_load_vec_array(&domain, &[[0.0; 1536], [1.0; 1536]])
+            .unwrap();
+
+        let query = |id: &str, vec: &LoadedVec, distance: u32| PointQuery {
+            id: 0,
+            point: Point::Stored {
+                id: id.to_string(),
+                vec: vec.clone(),
+            },
+            distance,
+        };
+        let points = vec![
+            query("Point/1", &e1, 3),
+            query("Point/2", &e2, 2),
+            query("Point/1", &e2, 1),
+        ];
+        let deduped = dedup_by_id(points);
+        assert_eq!(2, deduped.len());
+        assert_eq!("Point/1", deduped[0].id());
+        assert_eq!(1, deduped[0].distance());
+        assert_eq!("Point/2", deduped[1].id());
+    }
+
+    #[test]
+    fn duplicate_report_groups_pairs_into_clusters() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let hnsw = test_index(&store, &domain, 6, 0.0);
+
+        let report = duplicate_report(&hnsw, &[(0, 1), (2, 1), (4, 5)], 1);
+        assert_eq!(2, report.clusters);
+        assert_eq!(5, report.duplicated_documents);
+        assert_eq!(Some(&1), report.size_histogram.get(&3));
+        assert_eq!(Some(&1), report.size_histogram.get(&2));
+        assert_eq!(1, report.largest.len());
+        assert_eq!(vec!["doc0", "doc1", "doc2"], report.largest[0].sample_ids);
+        assert!((report.largest[0].mean_distance - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn split_index_by_ids() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let hnsw = test_index(&store, &domain, 4, 0.0);
+        let provenance = Provenance {
+            commit: "abc".to_string(),
+            line: 4,
+            content_hash: 1,
+        };
+        domain
+            .record_provenance(vec![(3, provenance.clone())])
+            .unwrap();
+        let mut tombstones = Tombstones::default();
+        tombstones.mark(2);
+
+        let matching = store.get_domain("matching").unwrap();
+        let rest = store.get_domain("rest").unwrap();
+        let ids = ["doc1".to_string(), "doc3".to_string()]
+            .into_iter()
+            .collect();
+        assert!(split_index(&hnsw, &tombstones, &store, &domain, &ids, &rest, &rest).is_err());
+        let (matching_hnsw, rest_hnsw) =
+            split_index(&hnsw, &tombstones, &store, &domain, &ids, &matching, &rest).unwrap();
+        assert_eq!(2, matching.num_vecs());
+        assert_eq!(1, rest.num_vecs());
+        let doc3 = find_point(&matching_hnsw, &Tombstones::default(), "doc3").unwrap();
+        assert_eq!(1.0, doc3.vec()[3]);
+        assert_eq!(
+            Some(&provenance),
+            matching
+                .provenance(&[doc3.vec_id()])
+                .unwrap()
+                .get(&doc3.vec_id())
+        );
+        assert!(find_point(&rest_hnsw, &Tombstones::default(), "doc3").is_none());
+        assert!(find_point(&rest_hnsw, &Tombstones::default(), "doc0").is_some());
+        assert!(find_point(&rest_hnsw, &Tombstones::default(), "doc2").is_none());
+    }
+
+    #[test]
+    fn read_metadata_without_deserializing() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo/bar").unwrap();
+        let hnsw = test_index(&store, &domain, 3, 0.0);
+        let name = create_index_name("foo/bar", "abc");
+        serialize_index(
+            tempdir.path().to_path_buf(),
+            &name,
+            &hnsw,
+            &Tombstones::default(),
+        )
+        .unwrap();
+
+        let metadata = read_index_metadata(tempdir.path().to_path_buf(), &name).unwrap();
+        assert_eq!("foo/bar", metadata.domain);
+        assert_eq!("abc", metadata.commit);
+        assert_eq!(3, metadata.vector_count);
+        assert_eq!(3, metadata.layer_sizes[0]);
+        assert!(metadata.checksum.is_some());
+        let listed = list_index_metadata(tempdir.path().to_path_buf(), Some("foo/bar")).unwrap();
+        assert_eq!(vec![metadata], listed);
+        assert!(
+            list_index_metadata(tempdir.path().to_path_buf(), Some("foo"))
+                .unwrap()
+                .is_empty()
+        );
+
+        // Stray files that are not named like an index are skipped.
+        for stray in ["%FF@abc.meta", "notes.meta"] {
+            std::fs::write(tempdir.path().join(stray), "{}").unwrap();
+        }
+        let listed = list_index_metadata(tempdir.path().to_path_buf(), None).unwrap();
+        assert_eq!(1, listed.len());
+    }
+
+    #[test]
+    fn torn_index_falls_back_to_previous_generation() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let hnsw = test_index(&store, &domain, 3, 0.0);
+        let name = create_index_name("foo", "abc");
+        let tombstones = Tombstones::default();
+        let mut size = 0;
+        for _ in 0..3 {
+            size =
+                serialize_index(tempdir.path().to_path_buf(), &name, &hnsw, &tombstones).unwrap();
+        }
+        let generation_path = |generation| {
+            tempdir
+                .path()
+                .join(generation_file_name(&name, generation, "hnsw"))
+        };
+        assert!(!generation_path(1).exists());
+        assert!(generation_path(2).exists());
+        let meta_path = tempdir.path().join(format!("{name}.meta"));
+        assert!(!temporary_path(&meta_path).exists());
+        // A generation written without its metadata is not read.
+        std::fs::write(generation_path(4), "{").unwrap();
+        let index = deserialize_index(&mut tempdir.path().to_path_buf(), &name, &store).unwrap();
+        assert_eq!(3, index.layer_len(0));
+
+        let tear = |generation| {
+            File::options()
+                .write(true)
+                .open(generation_path(generation))
+                .unwrap()
+                .set_len(size / 2)
+                .unwrap()
+        };
+        tear(3);
+        let index = deserialize_index(&mut tempdir.path().to_path_buf(), &name, &store).unwrap();
+        assert_eq!(3, index.layer_len(0));
+
+        tear(2);
+        let error = deserialize_index(&mut tempdir.path().to_path_buf(), &name, &store)
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    fn recall_of_a_small_index_is_exact() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let mut rng = Lcg128Xsl64::seed_from_u64(0);
+        assert_eq!(None, estimate_recall(&Hnsw::new(OpenAI), 3, 5, &mut rng));
+
+        let hnsw = test_index(&store, &domain, 20, 0.5);
+        assert_eq!(Some(1.0), estimate_recall(&hnsw, 3, 20, &mut rng));
+    }
+
+    #[test]
+    fn threshold_quality_of_labeled_pairs() {
+        let pairs = [(0.01, true), (0.02, true), (0.03, false), (0.2, true)];
+        let quality = threshold_quality(&pairs, &[0.025, 0.05, 0.5]);
+        assert_eq!(1.0, quality[0].precision);
+        assert_eq!(2.0 / 3.0, quality[0].recall);
+        assert_eq!(2.0 / 3.0, quality[1].precision);
+        assert_eq!(0.75, quality[2].precision);
+        assert_eq!(1.0, quality[2].recall);
+        assert_eq!(Some(0.02), percentile(&[0.01, 0.02, 0.03, 0.2], 50.0));
+        assert_eq!(Some(0.2), percentile(&[0.01, 0.02, 0.03, 0.2], 100.0));
+    }
+
+    #[test]
+    fn within_distance_drops_far_results() {
+        let query = |distance: f32| PointQuery {
+            id: 0,
+            point: Point::Mem {
+                vec: Box::new([0.0; 1536]),
+            },
+            distance: distance.to_bits(),
+        };
+        let points = vec![query(0.1), query(0.3), query(0.5)];
+        assert_eq!(2, within_distance(points.clone(), Some(0.3)).len());
+        assert_eq!(3, within_distance(points, None).len());
+    }
+
+    #[test]
+    fn provenance_of_repeated_ids() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let mut point_operations = test_operations(&store, &domain, "doc", 0..1, 0.0);
+        point_operations.extend(test_operations(&store, &domain, "doc", 0..1, 0.0));
+        let inserted = |string: &str| Operation::Inserted {
+            string: string.to_string(),
+            id: "doc0".to_string(),
+        };
+        let operations = vec![
+            (1, inserted("a")),
+            (
+                2,
+                Operation::Deleted {
+                    id: "doc0".to_string(),
+                },
+            ),
+            (3, inserted("b")),
+        ];
+
+        let provenance = provenance_for("abc", &operations, &point_operations);
+        let lines: Vec<(usize, usize)> = provenance.iter().map(|(vec, p)| (*vec, p.line)).collect();
+        assert_eq!(vec![(0, 1), (1, 3)], lines);
+        assert_eq!(0xaf63dc4c8601ec8c, provenance[0].1.content_hash);
+    }
+
+    #[test]
+    fn deleted_points_are_left_out() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let mut hnsw = test_index(&store, &domain, 4, 0.0);
+        let mut tombstones = Tombstones::default();
+        let mut id_index = IdIndex::new(&hnsw);
+
+        let mut e = [0.0; 1536];
+        e[5] = 1.0;
+        let mut operations = vec![
+            PointOperation::Delete {
+                id: "doc1".to_string(),
+            },
+            PointOperation::Replace {
+                point: Point::Stored {
+                    id: "doc2".to_string(),
+                    vec: store.add_and_load_vec(&domain, &e).unwrap(),
+                },
+            },
+        ];
+        // A document inserted and deleted by the same operations.
+        operations.extend(test_operations(&store, &domain, "new", 6..7, 0.0));
+        operations.push(PointOperation::Delete {
+            id: "new6".to_string(),
+        });
+        assert_eq!(
+            3,
+            apply_operations(&mut hnsw, &mut tombstones, &mut id_index, operations)
+        );
+        assert_eq!(&[2, 4], id_index.points("doc2"));
+
+        let name = create_index_name("foo", "abc");
+        serialize_index(tempdir.path().to_path_buf(), &name, &hnsw, &tombstones).unwrap();
+        let (hnsw, reloaded) =
+            deserialize_index_with_tombstones(&mut tempdir.path().to_path_buf(), &name, &store)
+                .unwrap();
+        assert_eq!(tombstones, reloaded);
+        assert_eq!(3, reloaded.len());
+
+        let query = Point::Mem {
+            vec: Box::new([0.0; 1536]),
+        };
+        let results = search_live(&query, 5, &hnsw, &tombstones).unwrap();
+        let mut ids: Vec<String> = results.iter().map(|p| p.id().to_string()).collect();
+        ids.sort();
+        assert_eq!(vec!["doc0", "doc2", "doc3"], ids);
+        assert_eq!(
+            1.0,
+            find_point(&hnsw, &tombstones, "doc2").unwrap().vec()[5]
+        );
+    }
+
+    #[test]
+    fn batch_search_matches_single_searches() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let hnsw = test_index(&store, &domain, 20, 0.5);
+
+        let queries: Vec<&Point> = (0..20).rev().map(|i| hnsw.feature(i)).collect();
+        let results = search_batch(&queries, 3, &hnsw, &Tombstones::default()).unwrap();
+        assert_eq!(queries.len(), results.len());
+        for (query, result) in queries.iter().zip(results) {
+            assert_eq!(search(query, 3, &hnsw).unwrap(), result);
+        }
+    }
+
+    #[test]
+    fn iterate_points_by_range_and_sample() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let hnsw = test_index(&store, &domain, 10, 0.0);
+
+        let none = Tombstones::default();
+        let ids: Vec<&str> = points_in_range(&hnsw, &none, 3..6)
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(vec!["doc3", "doc4", "doc5"], ids);
+        assert_eq!(2, points_in_range(&hnsw, &none, 8..100).count());
+        assert_eq!(0, points_in_range(&hnsw, &none, 20..30).count());
+
+        let sampled: Vec<&str> = sample_points(&hnsw, &none, 4, &mut rand::thread_rng())
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(4, sampled.len());
+        let mut sorted = sampled.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(4, sorted.len());
+
+        let mut tombstones = Tombstones::default();
+        tombstones.mark(4);
+        let ids: Vec<&str> = points_in_range(&hnsw, &tombstones, 3..6)
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(vec!["doc3", "doc5"], ids);
+        let sampled: Vec<&str> = sample_points(&hnsw, &tombstones, 20, &mut rand::thread_rng())
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(9, sampled.len());
+        assert!(!sampled.contains(&"doc4"));
+    }
+
+    #[test]
+    fn filtered_search_only_returns_admitted_points() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let hnsw = test_index(&store, &domain, 20, 0.5);
+        let query = hnsw.feature(6).clone();
+        let ids = |points: &[PointQuery]| -> Vec<String> {
+            points.iter().map(|p| p.id().to_string()).collect()
+        };
+
+        let allow = IdFilter::Allow(["doc7", "doc3", "doc12"].map(String::from).into());
+        let id_index = IdIndex::new(&hnsw);
+        let none = Tombstones::default();
+        let (points, statistics) =
+            search_filtered(&query, 2, &hnsw, &id_index, &allow, &none).unwrap();
+        assert_eq!(vec!["doc7", "doc3"], ids(&points));
+        assert_eq!(3, statistics.ef);
+
+        let deny = IdFilter::Deny((0..18).map(|i| format!("doc{i}")).collect());
+        let (points, _) = search_filtered(&query, 5, &hnsw, &id_index, &deny, &none).unwrap();
+        let mut found = ids(&points);
+        found.sort();
+        assert_eq!(vec!["doc18", "doc19"], found);
+
+        let mut tombstones = Tombstones::default();
+        tombstones.mark(7);
+        let (points, _) =
+            search_filtered(&query, 2, &hnsw, &id_index, &allow, &tombstones).unwrap();
+        assert_eq!(vec!["doc3", "doc12"], ids(&points));
+    }
+
+    #[test]
+    fn live_search_widens_past_deleted_points() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let hnsw = test_index(&store, &domain, 20, 0.5);
+        let query = hnsw.feature(0).clone();
+        // Far more of the nearest points are deleted than are asked for.
+        let mut tombstones = Tombstones::default();
+        for i in 0..15 {
+            tombstones.mark(i);
+        }
+        let points = search_live(&query, 3, &hnsw, &tombstones).unwrap();
+        assert_eq!(3, points.len());
+        assert!(points.iter().all(|p| p.internal_id() >= 15));
+    }
+
+    #[test]
+    fn binarized_search_reranks_exactly() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 4);
+        let domain = store.get_domain("foo").unwrap();
+        test_operations(&store, &domain, "doc", 0..20, 0.5);
+        assert_eq!(20, store.binarize_domain(&domain).unwrap());
+        let bits = store.load_binarized_domain(&domain).unwrap();
+        assert_eq!(20, bits.len());
+
+        // Equally far from vectors 6 and 7 by Hamming distance, but
+        // closer to 6 exactly.
+        let mut query = [0.0; 1536];
+        query[6] = 1.0;
+        query[7] = 0.5;
+        query[8] = 0.1;
+        let results = search_binarized(&query, 2, 1, &bits, &store, &domain).unwrap();
+        assert_eq!(vec![6, 7], results.iter().map(|r| r.0).collect::<Vec<_>>());
+        assert!(results[0].1 < results[1].1);
+
+        let results = search_binarized(&query, 3, 4, &bits, &store, &domain).unwrap();
+        assert_eq!(
+            vec![6, 7, 5],
+            results.iter().map(|r| r.0).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn neighbour_pairs_are_reported_once() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        // Points on a circle, unevenly spaced so no point has two
+        // equally near neighbours.
+        let operations = [0.0_f32, 0.1, 0.3, 1.0, 1.5]
+            .into_iter()
+            .enumerate()
+            .map(|(i, angle)| {
+                let mut e = [0.0; 1536];
+                e[0] = angle.cos();
+                e[1] = angle.sin();
+                PointOperation::Insert {
+                    point: Point::Stored {
+                        id: format!("doc{i}"),
+                        vec: store.add_and_load_vec(&domain, &e).unwrap(),
+                    },
+                }
+            })
+            .collect();
+        let hnsw = start_indexing_from_operations(Hnsw::new(OpenAI), operations).unwrap();
+
+        let pairs = neighbour_pairs(&hnsw, &Tombstones::default(), 1);
+        assert_eq!(
+            vec![(0, 1), (1, 2), (3, 4)],
+            pairs.iter().map(|&(i, j, _)| (i, j)).collect::<Vec<_>>()
+        );
+        let mut tombstones = Tombstones::default();
+        tombstones.mark(1);
+        let live_pairs = neighbour_pairs(&hnsw, &tombstones, 1);
+        assert_eq!(
+            vec![(0, 2), (3, 4)],
+            live_pairs
+                .iter()
+                .map(|&(i, j, _)| (i, j))
+                .collect::<Vec<_>>()
+        );
+        for (i, j, distance) in pairs {
+            assert_eq!(
+                f32::from_bits(OpenAI.distance(hnsw.feature(i), hnsw.feature(j))),
+                distance
+            );
+        }
+    }
+
+    #[test]
+    fn reorder_index_by_locality() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 4);
+        let domain = store.get_domain("foo").unwrap();
+        let hnsw = test_index(&store, &domain, 20, 0.5);
+
+        let order = locality_order(&hnsw, 4);
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!((0..20).collect::<Vec<_>>(), sorted);
+
+        let target = store.get_domain("bar").unwrap();
+        let reordered = reorder_index(hnsw.clone(), &store, &domain, &target, &order).unwrap();
+        assert_eq!(20, target.num_vecs());
+        for i in 0..20 {
+            assert_eq!(hnsw.feature(i).id(), reordered.feature(i).id());
+            assert_eq!(hnsw.feature(i).vec(), reordered.feature(i).vec());
+        }
+        let first = store.get_vec(&target, 0).unwrap().unwrap();
+        assert_eq!(hnsw.feature(order[0]).vec(), &*first);
+        let query = hnsw.feature(7);
+        assert_eq!(
+            search(query, 3, &hnsw).unwrap(),
+            search(query, 3, &reordered).unwrap()
+        );
+    }
+
+    #[test]
+    fn resume_from_checkpoint() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let hnsw = test_index(&store, &domain, 4, 0.0);
+        let name = create_index_name("foo", "bar");
+        assert!(read_checkpoint(tempdir.path(), &name, &store)
+            .unwrap()
+            .is_none());
+
+        let mut tombstones = Tombstones::default();
+        tombstones.mark(1);
+        write_checkpoint(tempdir.path(), &name, &hnsw, &tombstones, 4, 10).unwrap();
+        // Vectors added after the checkpoint, possibly for other
+        // indexes of the domain, are kept.
+        test_operations(&store, &domain, "doc", 4..6, 0.0);
+        let (resumed, resumed_tombstones, checkpoint) =
+            read_checkpoint(tempdir.path(), &name, &store)
+                .unwrap()
+                .unwrap();
+        assert_eq!(4, checkpoint.lines);
+        assert_eq!(10, checkpoint.quarantine_length);
+        assert_eq!(hnsw.layer_len(0), resumed.layer_len(0));
+        assert_eq!(tombstones, resumed_tombstones);
+        assert_eq!(6, domain.num_vecs());
+
+        remove_checkpoint(tempdir.path(), &name).unwrap();
+        assert!(read_checkpoint(tempdir.path(), &name, &store)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn find_duplicates_across_indexes() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let shard = |name: &str, dimensions: Range<usize>| {
+            let domain = store.get_domain(name).unwrap();
+            let operations = test_operations(&store, &domain, name, dimensions, 0.0);
+            start_indexing_from_operations(Hnsw::new(OpenAI), operations).unwrap()
+        };
+        // Only dimensions 2 and 3 are shared between the shards.
+        let first = shard("first", 0..4);
+        let second = shard("second", 2..6);
+        let none = Tombstones::default();
+
+        let mut pairs = cross_index_pairs(&[(&first, &none), (&second, &none)], 1, 0.01);
+        pairs.sort_by_key(|pair| pair.a);
+        let ids: Vec<(&str, &str)> = pairs
+            .iter()
+            .map(|pair| (first.feature(pair.a.1).id(), second.feature(pair.b.1).id()))
+            .collect();
+        assert_eq!(vec![("first2", "second2"), ("first3", "second3")], ids);
+        assert!(pairs.iter().all(|pair| pair.a.0 == 0 && pair.b.0 == 1));
+
+        // A deleted nearest neighbour gives way to the next one.
+        let domain = store.get_domain("third").unwrap();
+        let mut operations = test_operations(&store, &domain, "third", 2..4, 0.0);
+        operations.extend(test_operations(&store, &domain, "copy", 2..3, 0.0));
+        let third = start_indexing_from_operations(Hnsw::new(OpenAI), operations).unwrap();
+        let mut tombstones = Tombstones::default();
+        let deleted = (0..third.layer_len(0)).find(|&i| third.feature(i).id() == "third2");
+        tombstones.mark(deleted.unwrap());
+        let mut pairs = cross_index_pairs(&[(&first, &none), (&third, &tombstones)], 1, 0.01);
+        pairs.sort_by_key(|pair| pair.a);
+        let ids: Vec<(&str, &str)> = pairs
+            .iter()
+            .map(|pair| (first.feature(pair.a.1).id(), third.feature(pair.b.1).id()))
+            .collect();
+        assert_eq!(vec![("first2", "copy2"), ("first3", "third3")], ids);
+    }
+
+    #[test]
+    fn top_k_keeps_the_smallest_keys() {
+        let mut top = TopK::new(3);
+        for (key, item) in [(5, 'a'), (1, 'b'), (4, 'c'), (9, 'd'), (2, 'e'), (3, 'f')] {
+            top.push(key, item);
+        }
+        assert_eq!(vec![(1, 'b'), (2, 'e'), (3, 'f')], top.into_sorted_vec());
+
+        let mut empty = TopK::new(0);
+        empty.push(1, ());
+        assert!(empty.into_sorted_vec().is_empty());
+
+        let mut unbounded = TopK::new(usize::MAX);
+        unbounded.push(1, ());
+        assert_eq!(vec![(1, ())], unbounded.into_sorted_vec());
+    }
 }
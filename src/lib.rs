@@ -1,3 +1,4 @@
+pub mod client;
 pub mod indexer;
 pub mod openai;
 pub mod server;
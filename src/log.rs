@@ -0,0 +1,76 @@
+#![allow(unused, dead_code)]
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::OnceLock;
+
+tokio::task_local! {
+    pub(crate) static TRACE_ID: String;
+    pub(crate) static TASK_CONTEXT: TaskContext;
+}
+
+/// The indexing task a log line was written from.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct TaskContext {
+    pub task_id: String,
+    pub domain: String,
+    pub commit: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One json object per line, for log shippers.
+    Json,
+}
+
+static LOG_FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// Set the format of log lines. Only the first call has an effect.
+pub fn set_log_format(format: LogFormat) {
+    let _ = LOG_FORMAT.set(format);
+}
+
+pub(crate) fn log_line(message: std::fmt::Arguments) {
+    let time = chrono::offset::Local::now();
+    let trace_id = current_trace_id();
+    let context = TASK_CONTEXT.try_with(|context| context.clone()).ok();
+    match LOG_FORMAT.get().copied().unwrap_or_default() {
+        LogFormat::Text => match context {
+            Some(TaskContext {
+                task_id,
+                domain,
+                commit,
+            }) => eprintln!("{time:?}: [{trace_id}] [{task_id} {domain} {commit}] {message}"),
+            None => eprintln!("{time:?}: [{trace_id}] {message}"),
+        },
+        LogFormat::Json => {
+            let mut line = json!({
+                "time": time.to_rfc3339(),
+                "trace_id": trace_id,
+                "message": message.to_string(),
+            });
+            if let Some(context) = context {
+                line["task_id"] = context.task_id.into();
+                line["domain"] = context.domain.into();
+                line["commit"] = context.commit.into();
+            }
+            eprintln!("{line}");
+        }
+    }
+}
+
+/// The trace id of the request being handled, or `-` outside of one.
+pub(crate) fn current_trace_id() -> String {
+    TRACE_ID
+        .try_with(|trace_id| trace_id.clone())
+        .unwrap_or_else(|_| "-".to_string())
+}
+
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log::log_line(format_args!($($arg)*))
+    };
+}
+pub(crate) use log;
@@ -1,23 +1,39 @@
-use std::io::ErrorKind;
-use std::path::Path;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 
 use clap::CommandFactory;
 use clap::{Parser, Subcommand, ValueEnum};
-use hnsw::Hnsw;
+use client::Client;
 use indexer::serialize_index;
-use indexer::start_indexing_from_operations;
 use indexer::Point;
-use indexer::{operations_to_point_operations, OpenAI};
-use server::Operation;
+use indexer::{
+    apply_operations, calibration_distances, cross_index_pairs, deserialize_index,
+    deserialize_index_with_tombstones, duplicate_report_by, find_point, locality_order,
+    model_for_indexing, neighbour_pairs, new_index, operations_to_point_operations, percentile,
+    points_in_range, provenance_for, read_checkpoint, read_index_metadata, remove_checkpoint,
+    reorder_index, sample_points, search_binarized, split_index, stable_hash, threshold_quality,
+    write_checkpoint, IdIndex, IndexError, InvalidEmbeddingPolicy, OpenAI, QuarantineRecord,
+    Tombstones,
+};
+use log::{log, LogFormat};
+use openai::{CircuitBreaker, EmbeddingCache, EmbeddingProvider, EmbeddingUsage, Model};
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use serde_json::json;
+use server::{Operation, DUPLICATE_REPORT_CLUSTERS};
 use space::Metric;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Seek, SeekFrom, Write};
+use std::time::Duration;
 use {
     indexer::{create_index_name, HnswIndex},
-    vecmath::empty_embedding,
-    vectors::VectorStore,
+    vecmath::{empty_embedding, Embedding},
+    vectors::{SynthParameters, VectorStore},
 };
+mod client;
 mod indexer;
+mod log;
 mod openai;
 mod server;
 mod vecmath;
@@ -29,6 +45,49 @@ use itertools::Itertools;
 struct Args {
     #[command(subcommand)]
     command: Commands,
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ProviderKind {
+    Openai,
+    Azure,
+    Local,
+}
+
+#[derive(clap::Args, Debug)]
+struct ProviderArgs {
+    #[arg(long, value_enum, default_value_t = ProviderKind::Openai)]
+    embedding_provider: ProviderKind,
+    #[arg(long)]
+    embedding_endpoint: Option<String>,
+    #[arg(long)]
+    azure_deployment: Option<String>,
+    #[arg(long, default_value = "2023-05-15")]
+    azure_api_version: String,
+}
+
+impl ProviderArgs {
+    fn provider(&self) -> Result<EmbeddingProvider, String> {
+        let endpoint = || {
+            self.embedding_endpoint
+                .as_deref()
+                .ok_or_else(|| "--embedding-endpoint is required for this provider".to_string())
+        };
+        match self.embedding_provider {
+            ProviderKind::Openai => Ok(EmbeddingProvider::OpenAI),
+            ProviderKind::Azure => {
+                let deployment = self
+                    .azure_deployment
+                    .as_deref()
+                    .ok_or_else(|| "--azure-deployment is required for azure".to_string())?;
+                EmbeddingProvider::azure(endpoint()?, deployment, &self.azure_api_version)
+                    .map_err(|e| e.to_string())
+            }
+            ProviderKind::Local => EmbeddingProvider::local(endpoint()?).map_err(|e| e.to_string()),
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -44,6 +103,30 @@ enum Commands {
         port: u16,
         #[arg(short, long, default_value_t = 10000)]
         size: usize,
+        #[arg(long, default_value_t = 0)]
+        embedding_cache_size: usize,
+        #[arg(long, default_value_t = 5)]
+        embedding_failure_threshold: usize,
+        #[arg(long, default_value_t = 30)]
+        embedding_reset_secs: u64,
+        #[arg(long, value_enum, default_value_t = InvalidEmbeddingPolicy::Fail)]
+        invalid_embedding_policy: InvalidEmbeddingPolicy,
+        #[arg(long)]
+        slow_query_ms: Option<u64>,
+        #[arg(long)]
+        file_access_key: Option<String>,
+        #[arg(long, default_value_t = 0)]
+        query_cache_size: usize,
+        #[arg(long, default_value_t = 60)]
+        query_cache_ttl_secs: u64,
+        #[arg(long)]
+        preview_interval: Option<NonZeroUsize>,
+        #[arg(long)]
+        max_concurrent_requests: Option<usize>,
+        #[arg(long, default_value_t = NonZeroUsize::MIN)]
+        background_scans: NonZeroUsize,
+        #[command(flatten)]
+        provider: ProviderArgs,
     },
     Load {
         #[arg(short, long)]
@@ -58,12 +141,62 @@ enum Commands {
         input: String,
         #[arg(short, long, default_value_t = 10000)]
         size: usize,
+        #[arg(short, long)]
+        model: Option<Model>,
+        #[arg(long)]
+        allow_model_mismatch: bool,
+        #[arg(long)]
+        in_memory: bool,
+        #[arg(long, default_value_t = IN_MEMORY_BUILD_THRESHOLD)]
+        in_memory_threshold: usize,
+        #[arg(short, long)]
+        quarantine: Option<String>,
+        #[arg(long)]
+        from_quarantine: bool,
+        #[arg(long, value_enum, default_value_t = InvalidEmbeddingPolicy::Fail)]
+        invalid_embedding_policy: InvalidEmbeddingPolicy,
+        #[arg(long)]
+        seed: Option<u64>,
+        #[arg(short, long)]
+        previous: Option<String>,
+        #[arg(long)]
+        checkpoint_interval: Option<usize>,
+        #[arg(long)]
+        resume: bool,
+        #[arg(long, default_value_t = 0)]
+        embedding_cache_size: usize,
+        #[command(flatten)]
+        provider: ProviderArgs,
     },
     Embed {
         #[arg(short, long)]
         key: Option<String>,
         #[arg(short, long)]
         string: String,
+        #[arg(short, long, default_value_t=Model::ADA2)]
+        model: Model,
+        #[command(flatten)]
+        provider: ProviderArgs,
+    },
+    EmbedBatch {
+        #[arg(short, long)]
+        key: Option<String>,
+        #[arg(short, long)]
+        input: String,
+        #[arg(short, long)]
+        output: String,
+        #[arg(long)]
+        field: Option<String>,
+        #[arg(long, default_value = "id")]
+        id_field: String,
+        #[arg(long, default_value_t = 100)]
+        batch_size: usize,
+        #[arg(long)]
+        requests_per_minute: Option<u32>,
+        #[arg(short, long, default_value_t=Model::ADA2)]
+        model: Model,
+        #[command(flatten)]
+        provider: ProviderArgs,
     },
     Compare {
         #[arg(short, long)]
@@ -72,6 +205,8 @@ enum Commands {
         s1: String,
         #[arg(long)]
         s2: String,
+        #[arg(short, long, default_value_t=Model::ADA2)]
+        model: Model,
     },
     Compare2 {
         #[arg(short, long)]
@@ -82,13 +217,242 @@ enum Commands {
         s2: String,
         #[arg(short, long, value_enum, default_value_t=DistanceVariant::Default)]
         variant: DistanceVariant,
+        #[arg(short, long, default_value_t=Model::ADA2)]
+        model: Model,
+    },
+    DomainStats {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+        #[arg(short, long, default_value_t = 10)]
+        outliers: usize,
+        /// Name outliers by their document id in the index of this
+        /// commit
+        #[arg(short, long)]
+        commit: Option<String>,
+    },
+    IndexStats {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long)]
+        commit: String,
+    },
+    Export {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long)]
+        commit: String,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+        #[arg(short, long)]
+        output: Option<String>,
+        #[arg(long, default_value_t = 0)]
+        start: usize,
+        #[arg(long)]
+        end: Option<usize>,
+        #[arg(long, conflicts_with_all = ["start", "end"])]
+        sample: Option<usize>,
+    },
+    DistanceMatrix {
+        #[arg(short, long)]
+        directory: Option<String>,
+        #[arg(long, requires_all = ["directory", "commit"])]
+        domain: Option<String>,
+        #[arg(short, long)]
+        commit: Option<String>,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+        #[arg(short, long, conflicts_with = "domain")]
+        input: Option<String>,
+        #[arg(short, long, value_enum, default_value_t=MatrixFormat::Csv)]
+        format: MatrixFormat,
+        ids: Vec<String>,
+    },
+    Split {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long)]
+        commit: String,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+        #[arg(short, long)]
+        ids: String,
+        #[arg(long)]
+        matching_domain: String,
+        #[arg(long)]
+        rest_domain: String,
+    },
+    Reorder {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long)]
+        commit: String,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+        #[arg(long)]
+        target_domain: String,
+        #[arg(long, default_value_t = 16)]
+        neighbours: usize,
+    },
+    CrossDuplicates {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long, required = true)]
+        shard: Vec<String>,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+        #[arg(short, long)]
+        threshold: f32,
+        #[arg(long, default_value_t = 1)]
+        neighbours: usize,
+        #[arg(long)]
+        report: bool,
+    },
+    TrainingPairs {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long)]
+        commit: String,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+        #[arg(short, long)]
+        input: String,
+        #[arg(short, long)]
+        output: String,
+        #[arg(long, default_value_t = 5)]
+        neighbours: usize,
+        #[arg(long, default_value_t = 10)]
+        bands: usize,
+        #[arg(long, default_value_t = 0.2)]
+        max_distance: f32,
+        #[arg(long, default_value_t = 100)]
+        per_band: usize,
+    },
+    Calibrate {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long)]
+        commit: String,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+        #[arg(long, default_value_t = 1000)]
+        samples: usize,
+        #[arg(long)]
+        labels: Option<String>,
+        #[arg(long, default_value_t = 0.95)]
+        target_precision: f32,
+    },
+    Synth {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long)]
+        count: usize,
+        #[arg(long, default_value_t = 100)]
+        clusters: usize,
+        #[arg(long, default_value_t = 1536)]
+        dimension: usize,
+        #[arg(long, default_value_t = 0.05)]
+        spread: f32,
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+    },
+    Search {
+        #[arg(long, default_value = "http://localhost:8080")]
+        server: String,
+        #[arg(short, long)]
+        key: Option<String>,
+        #[arg(long)]
+        domain: String,
+        #[arg(long)]
+        commit: String,
+        #[arg(short, long, default_value_t = 10)]
+        count: usize,
+        #[arg(short, long)]
+        query: String,
+    },
+    Index {
+        #[arg(long, default_value = "http://localhost:8080")]
+        server: String,
+        #[arg(short, long)]
+        key: Option<String>,
+        #[arg(long)]
+        domain: String,
+        #[arg(long)]
+        commit: String,
+        #[arg(long)]
+        previous: Option<String>,
+        #[arg(long)]
+        wait: bool,
+    },
+    Binarize {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+    },
+    SearchBinarized {
+        #[arg(short, long)]
+        directory: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+        #[arg(short, long)]
+        key: Option<String>,
+        #[arg(short, long)]
+        model: Option<Model>,
+        #[arg(long)]
+        allow_model_mismatch: bool,
+        #[arg(short, long, default_value_t = 10)]
+        count: usize,
+        /// How many times `count` candidates to take by Hamming
+        /// distance before ordering them by their exact distance
+        #[arg(long, default_value_t = 10)]
+        rerank: usize,
+        #[arg(short, long)]
+        query: String,
+        #[command(flatten)]
+        provider: ProviderArgs,
     },
     Test {
         #[arg(short, long)]
         key: Option<String>,
+        #[arg(short, long, default_value_t=Model::ADA2)]
+        model: Model,
     },
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum MatrixFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Deserialize)]
+struct ExportedVector {
+    id: String,
+    vector: Vec<f32>,
+}
+
 #[derive(Clone, Copy, Debug, ValueEnum)]
 enum DistanceVariant {
     Default,
@@ -96,11 +460,173 @@ enum DistanceVariant {
     Scalar,
 }
 
+// Loading a domain of up to this many vectors (about 600MB) by default
+// reserves room in the vector arena for all of its pages, so that none
+// are evicted and reloaded while its index is built.
+const IN_MEMORY_BUILD_THRESHOLD: usize = 100_000;
+
+/// Estimate the number of lines in a file from the length of its first
+/// lines, rather than reading all of it.
+fn estimate_line_count(path: &Path) -> io::Result<usize> {
+    const SAMPLE_LINES: usize = 1000;
+    let len = std::fs::metadata(path)?.len() as usize;
+    let mut reader = io::BufReader::new(File::open(path)?);
+    let mut line = Vec::new();
+    let mut sampled_bytes = 0;
+    for count in 0..SAMPLE_LINES {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            return Ok(count);
+        }
+        sampled_bytes += read;
+    }
+    Ok(len * SAMPLE_LINES / sampled_bytes)
+}
+
+fn write_quarantine_record<W: Write>(
+    quarantine: &mut W,
+    line: usize,
+    error: String,
+    operation: String,
+) -> io::Result<()> {
+    let record = QuarantineRecord {
+        line,
+        error,
+        operation,
+    };
+    serde_json::to_writer(&mut *quarantine, &record)?;
+    writeln!(quarantine)
+}
+
+/// Whether both paths name the same existing file.
+fn is_same_file(left: &Path, right: &Path) -> io::Result<bool> {
+    match (left.canonicalize(), right.canonicalize()) {
+        (Ok(left), Ok(right)) => Ok(left == right),
+        (Err(e), _) | (_, Err(e)) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    }
+}
+
+// Operations files given as URLs are downloaded into this
+// subdirectory of the storage directory, and removed once loaded.
+const DOWNLOAD_DIRECTORY: &str = ".downloads";
+
+// How often a failing download is tried before giving up.
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+
+/// Download an operations file into the storage directory, resuming
+/// an earlier download of the same URL if it was interrupted.
+async fn download_operations(
+    url: &str,
+    directory: &Path,
+) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let download_directory = directory.join(DOWNLOAD_DIRECTORY);
+    std::fs::create_dir_all(&download_directory)?;
+    // Downloads are kept under the version of the file, so one of an
+    // older version is never picked up, whether finished or not.
+    let version = match remote_version(url).await {
+        Ok(version) => version,
+        Err(e) => {
+            log!("could not check the version of {url}: {e}");
+            None
+        }
+    };
+    let key = match &version {
+        Some(version) => format!("{url}\n{version}"),
+        None => url.to_string(),
+    };
+    let name = format!("{:016x}", stable_hash(key.as_bytes()));
+    let complete = download_directory.join(format!("{name}.ops"));
+    let partial = download_directory.join(format!("{name}.partial"));
+    if version.is_none() {
+        // Without a version, what an earlier download left may be out
+        // of date.
+        for path in [&complete, &partial] {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+    } else if complete.exists() {
+        return Ok(complete);
+    }
+    let mut attempt = 1;
+    loop {
+        match download_to(url, version.as_deref(), &partial).await {
+            Ok(()) => break,
+            Err(e) if attempt < DOWNLOAD_ATTEMPTS => {
+                log!("download of {url} failed, retrying: {e}");
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    std::fs::rename(&partial, &complete)?;
+
+    Ok(complete)
+}
+
+/// The version of the file at `url`, taken from its `ETag` or else its
+/// `Last-Modified` header, if the server sends either.
+async fn remote_version(
+    url: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let response = reqwest::Client::new()
+        .head(url)
+        .send()
+        .await?
+        .error_for_status()?;
+    let headers = response.headers();
+    Ok(headers
+        .get(reqwest::header::ETAG)
+        .or_else(|| headers.get(reqwest::header::LAST_MODIFIED))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string))
+}
+
+/// Download `url` into `partial`, continuing after what an earlier
+/// attempt left there if the server supports range requests. The rest
+/// is only asked for if the file still has `version`, and is
+/// downloaded in full otherwise.
+async fn download_to(
+    url: &str,
+    version: Option<&str>,
+    partial: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::options().create(true).append(true).open(partial)?;
+    let offset = file.metadata()?.len();
+    let mut request = reqwest::Client::new().get(url);
+    if let Some(version) = version.filter(|_| offset != 0) {
+        log!("resuming download of {url} at byte {offset}");
+        request = request
+            .header(reqwest::header::RANGE, format!("bytes={offset}-"))
+            .header(reqwest::header::IF_RANGE, version);
+    }
+    let mut response = request.send().await?;
+    if offset != 0 && response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // The previous download got everything but was not renamed.
+        return Ok(());
+    }
+    response = response.error_for_status()?;
+    if offset != 0 && response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        // The server ignored the range, so start over.
+        file.set_len(0)?;
+    }
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk)?;
+    }
+    file.sync_data()?;
+
+    Ok(())
+}
+
 fn key_or_env(k: Option<String>) -> String {
     let result = k.or_else(|| std::env::var("OPENAI_KEY").ok());
     if result.is_none() {
         let mut app = Args::command();
-        eprintln!("Error: no OpenAI key given. Configure it with the OPENAI_KEY environment variable, or by passing in the --key argument");
+        log!("Error: no OpenAI key given. Configure it with the OPENAI_KEY environment variable, or by passing in the --key argument");
         app.print_help().unwrap();
         std::process::exit(2);
     }
@@ -108,17 +634,37 @@ fn key_or_env(k: Option<String>) -> String {
     result.unwrap()
 }
 
+/// The api key for `provider`, which may be left out if the provider
+/// does not need one.
+fn provider_key_or_env(provider: &EmbeddingProvider, k: Option<String>) -> String {
+    if provider.needs_key() {
+        key_or_env(k)
+    } else {
+        k.unwrap_or_default()
+    }
+}
+
 fn content_endpoint_or_env(c: Option<String>) -> Option<String> {
     c.or_else(|| std::env::var("TERMINUSDB_CONTENT_ENDPOINT").ok())
 }
 
+fn file_access_key_or_env(c: Option<String>) -> Option<String> {
+    c.or_else(|| std::env::var("VECTORLINK_FILE_ACCESS_KEY").ok())
+}
+
 fn user_forward_header_or_env(c: Option<String>) -> String {
     c.unwrap_or_else(|| std::env::var("TERMINUSDB_USER_FORWARD_HEADER").unwrap())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Models are parsed along with the arguments, so the registry has
+    // to be read before them.
+    if let Ok(path) = std::env::var("VECTORLINK_MODEL_REGISTRY") {
+        openai::load_model_registry(Path::new(&path))?;
+    }
     let args = Args::parse();
+    log::set_log_format(args.log_format);
     match args.command {
         Commands::Serve {
             content_endpoint,
@@ -126,6 +672,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             directory,
             port,
             size,
+            embedding_cache_size,
+            embedding_failure_threshold,
+            embedding_reset_secs,
+            invalid_embedding_policy,
+            slow_query_ms,
+            file_access_key,
+            query_cache_size,
+            query_cache_ttl_secs,
+            preview_interval,
+            max_concurrent_requests,
+            background_scans,
+            provider,
         } => {
             server::serve(
                 directory,
@@ -133,15 +691,106 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 port,
                 size,
                 content_endpoint_or_env(content_endpoint),
+                EmbeddingCache::new(
+                    embedding_cache_size,
+                    provider.provider()?,
+                    CircuitBreaker::new(
+                        embedding_failure_threshold,
+                        Duration::from_secs(embedding_reset_secs),
+                    ),
+                ),
+                invalid_embedding_policy,
+                slow_query_ms.map(Duration::from_millis),
+                file_access_key_or_env(file_access_key),
+                query_cache_size,
+                Duration::from_secs(query_cache_ttl_secs),
+                preview_interval,
+                max_concurrent_requests,
+                background_scans,
             )
             .await?
         }
-        Commands::Embed { key, string } => {
-            let v: Vec<[f32; 1536]> = openai::embeddings_for(&key_or_env(key), &[string]).await?;
-            eprintln!("{:?}", v);
+        Commands::Embed {
+            key,
+            string,
+            model,
+            provider,
+        } => {
+            let provider = provider.provider()?;
+            let key = provider_key_or_env(&provider, key);
+            let embeddings = provider
+                .embeddings_for(&key, &[model.query_text(&string)], model)
+                .await?;
+            let output = json!({
+                "model": model,
+                "model_version": embeddings.model,
+                "usage": embeddings.usage,
+                "embedding": &embeddings.embeddings[0][..],
+            });
+            println!("{output}");
         }
-        Commands::Compare { key, s1, s2 } => {
-            let v = openai::embeddings_for(&key_or_env(key), &[s1, s2]).await?;
+        Commands::EmbedBatch {
+            key,
+            input,
+            output,
+            field,
+            id_field,
+            batch_size,
+            requests_per_minute,
+            model,
+            provider,
+        } => {
+            let provider = provider.provider()?;
+            let key = provider_key_or_env(&provider, key);
+            let mut entries = Vec::new();
+            for (line_number, line) in io::BufReader::new(File::open(input)?).lines().enumerate() {
+                let line = line?;
+                match &field {
+                    Some(field) => {
+                        let value: serde_json::Value = serde_json::from_str(&line)?;
+                        let text = value[field].as_str().ok_or_else(|| {
+                            format!("line {line_number} has no string field {field}")
+                        })?;
+                        let id = match &value[&id_field] {
+                            serde_json::Value::String(id) => id.clone(),
+                            serde_json::Value::Null => line_number.to_string(),
+                            id => id.to_string(),
+                        };
+                        entries.push((id, text.to_string()));
+                    }
+                    None => entries.push((line_number.to_string(), line)),
+                }
+            }
+
+            let pause = requests_per_minute.map(|rpm| Duration::from_secs(60) / rpm.max(1));
+            let mut usage = EmbeddingUsage::default();
+            let mut vecs = io::BufWriter::new(File::create(format!("{output}.vecs"))?);
+            let mut ids = io::BufWriter::new(File::create(format!("{output}.ids"))?);
+            for (batch_number, batch) in entries.chunks(batch_size.max(1)).enumerate() {
+                let start = std::time::Instant::now();
+                let strings: Vec<String> = batch
+                    .iter()
+                    .map(|(_, text)| model.document_text(text))
+                    .collect();
+                let embeddings = provider.embeddings_for(&key, &strings, model).await?;
+                usage += embeddings.usage;
+                for ((id, _), embedding) in batch.iter().zip(embeddings.embeddings.iter()) {
+                    for value in embedding {
+                        vecs.write_all(&value.to_ne_bytes())?;
+                    }
+                    writeln!(ids, "{id}")?;
+                }
+                log!("embedded batch {batch_number} ({} strings)", batch.len());
+                if let Some(pause) = pause {
+                    tokio::time::sleep(pause.saturating_sub(start.elapsed())).await;
+                }
+            }
+            vecs.flush()?;
+            ids.flush()?;
+            log!("{}", json!({ "model": model, "usage": usage }));
+        }
+        Commands::Compare { key, s1, s2, model } => {
+            let v = openai::embeddings_for(&key_or_env(key), &[s1, s2], model).await?;
             let p1 = Point::Mem {
                 vec: Box::new(v[0]),
             };
@@ -159,8 +808,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             s1,
             s2,
             variant,
+            model,
         } => {
-            let v = openai::embeddings_for(&key_or_env(key), &[s1, s2]).await?;
+            let v = openai::embeddings_for(&key_or_env(key), &[s1, s2], model).await?;
             let p1 = &v[0];
             let p2 = &v[1];
             let distance = match variant {
@@ -170,7 +820,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             };
             println!("distance: {}", distance);
         }
-        Commands::Test { key } => {
+        Commands::Test { key, model } => {
             let v = openai::embeddings_for(
                 &key_or_env(key),
                 &[
@@ -179,6 +829,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                     "woman".to_string(),
                     "queen".to_string(),
                 ],
+                model,
             )
             .await?;
             let mut calculated = empty_embedding();
@@ -186,7 +837,503 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 *calculated = v[0][i] - v[1][i] + v[2][i];
             }
             let distance = vecmath::normalized_cosine_distance(&v[3], &calculated);
-            eprintln!("{}", distance);
+            log!("{}", distance);
+        }
+        Commands::DomainStats {
+            directory,
+            domain,
+            size,
+            outliers,
+            commit,
+        } => {
+            let dirpath = Path::new(&directory);
+            let store = VectorStore::new(dirpath, size);
+            let resolved_domain = store.get_domain(&domain)?;
+            let mut statistics = store.domain_statistics(&resolved_domain, outliers)?;
+            if let Some(commit) = commit {
+                let index_id = create_index_name(&domain, &commit);
+                let hnsw = deserialize_index(&mut dirpath.to_path_buf(), &index_id, &store)?;
+                let ids: HashMap<usize, &str> = (0..hnsw.layer_len(0))
+                    .map(|i| (hnsw.feature(i).vec_id(), hnsw.feature(i).id()))
+                    .collect();
+                statistics.identify_outliers(|index| ids.get(&index).map(|id| id.to_string()));
+            }
+            println!("{}", serde_json::to_string_pretty(&statistics)?);
+        }
+        Commands::IndexStats {
+            directory,
+            domain,
+            commit,
+        } => {
+            let dirpath = Path::new(&directory);
+            let index_id = create_index_name(&domain, &commit);
+            let metadata = read_index_metadata(dirpath.to_path_buf(), &index_id)?;
+            let store = VectorStore::new(dirpath, 1);
+            let model = store.domain_model(&domain)?.unwrap_or_default();
+            let mut stats = serde_json::to_value(metadata)?;
+            stats["model"] = json!(model);
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+        Commands::Export {
+            directory,
+            domain,
+            commit,
+            size,
+            output,
+            start,
+            end,
+            sample,
+        } => {
+            let dirpath = Path::new(&directory);
+            let store = VectorStore::new(dirpath, size);
+            let index_id = create_index_name(&domain, &commit);
+            let (hnsw, tombstones) =
+                deserialize_index_with_tombstones(&mut dirpath.to_path_buf(), &index_id, &store)?;
+            let mut output: Box<dyn Write> = match output {
+                Some(output) => Box::new(io::BufWriter::new(File::create(output)?)),
+                None => Box::new(io::BufWriter::new(io::stdout())),
+            };
+            let points: Box<dyn Iterator<Item = _>> = match sample {
+                Some(count) => Box::new(sample_points(
+                    &hnsw,
+                    &tombstones,
+                    count,
+                    &mut rand::thread_rng(),
+                )),
+                None => Box::new(points_in_range(
+                    &hnsw,
+                    &tombstones,
+                    start..end.unwrap_or(usize::MAX),
+                )),
+            };
+            for (id, vec) in points {
+                serde_json::to_writer(&mut output, &json!({"id": id, "vector": &vec[..]}))?;
+                writeln!(output)?;
+            }
+            output.flush()?;
+        }
+        Commands::DistanceMatrix {
+            directory,
+            domain,
+            commit,
+            size,
+            input,
+            format,
+            ids,
+        } => {
+            let vectors: Vec<(String, Embedding)> = match (input, domain) {
+                (Some(input), _) => io::BufReader::new(File::open(input)?)
+                    .lines()
+                    .map(
+                        |line| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+                            let exported: ExportedVector = serde_json::from_str(&line?)?;
+                            let vector: Embedding = exported.vector.try_into().map_err(|_| {
+                                format!("vector for {} has the wrong length", exported.id)
+                            })?;
+                            Ok((exported.id, vector))
+                        },
+                    )
+                    .filter_ok(|(id, _)| ids.is_empty() || ids.contains(id))
+                    .collect::<Result<_, _>>()?,
+                (None, Some(domain)) => {
+                    let dirpath = Path::new(directory.as_ref().unwrap());
+                    let store = VectorStore::new(dirpath, size);
+                    let index_id = create_index_name(&domain, commit.as_ref().unwrap());
+                    let (hnsw, tombstones) = deserialize_index_with_tombstones(
+                        &mut dirpath.to_path_buf(),
+                        &index_id,
+                        &store,
+                    )?;
+                    ids.iter()
+                        .map(|id| match find_point(&hnsw, &tombstones, id) {
+                            Some(point) => Ok((id.clone(), *point.vec())),
+                            None => Err(format!("id not found in index: {id}")),
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                (None, None) => {
+                    log!("Error: either --input or --domain must be given");
+                    std::process::exit(2);
+                }
+            };
+            let embeddings: Vec<&Embedding> = vectors.iter().map(|(_, v)| v).collect();
+            let matrix = vecmath::distance_matrix(&embeddings);
+            match format {
+                MatrixFormat::Csv => {
+                    println!(",{}", vectors.iter().map(|(id, _)| id).join(","));
+                    for ((id, _), row) in vectors.iter().zip(matrix) {
+                        println!("{id},{}", row.iter().join(","));
+                    }
+                }
+                MatrixFormat::Json => {
+                    let ids: Vec<&String> = vectors.iter().map(|(id, _)| id).collect();
+                    println!("{}", json!({"ids": ids, "distances": matrix}));
+                }
+            }
+        }
+        Commands::Split {
+            directory,
+            domain,
+            commit,
+            size,
+            ids,
+            matching_domain,
+            rest_domain,
+        } => {
+            let dirpath = Path::new(&directory);
+            let store = VectorStore::new(dirpath, size);
+            let source = store.get_domain(&domain)?;
+            let index_id = create_index_name(&domain, &commit);
+            let (hnsw, tombstones) =
+                deserialize_index_with_tombstones(&mut dirpath.to_path_buf(), &index_id, &store)?;
+            // One external id per line
+            let ids: HashSet<String> = io::BufReader::new(File::open(ids)?)
+                .lines()
+                .collect::<io::Result<_>>()?;
+            let matching = store.get_domain(&matching_domain)?;
+            let rest = store.get_domain(&rest_domain)?;
+            for part in [&matching, &rest] {
+                if part.num_vecs() != 0 {
+                    log!("Error: can only split into empty domains");
+                    std::process::exit(2);
+                }
+                part.copy_model_from(&source)?;
+            }
+            let (matching_hnsw, rest_hnsw) =
+                split_index(&hnsw, &tombstones, &store, &source, &ids, &matching, &rest)?;
+            log!(
+                "{} documents matched, {} did not",
+                matching_hnsw.layer_len(0),
+                rest_hnsw.layer_len(0)
+            );
+            for (name, hnsw) in [(matching_domain, matching_hnsw), (rest_domain, rest_hnsw)] {
+                serialize_index(
+                    dirpath.to_path_buf(),
+                    &create_index_name(&name, &commit),
+                    &hnsw,
+                    &Tombstones::default(),
+                )?;
+            }
+        }
+        Commands::Reorder {
+            directory,
+            domain,
+            commit,
+            size,
+            target_domain,
+            neighbours,
+        } => {
+            let dirpath = Path::new(&directory);
+            let store = VectorStore::new(dirpath, size);
+            let source = store.get_domain(&domain)?;
+            let index_id = create_index_name(&domain, &commit);
+            let (hnsw, tombstones) =
+                deserialize_index_with_tombstones(&mut dirpath.to_path_buf(), &index_id, &store)?;
+            let target = store.get_domain(&target_domain)?;
+            if target.num_vecs() != 0 {
+                log!("Error: can only reorder into an empty domain");
+                std::process::exit(2);
+            }
+            target.copy_model_from(&source)?;
+            let order = locality_order(&hnsw, neighbours);
+            let hnsw = reorder_index(hnsw, &store, &source, &target, &order)?;
+            log!(
+                "copied {} of {} vectors",
+                target.num_vecs(),
+                source.num_vecs()
+            );
+            serialize_index(
+                dirpath.to_path_buf(),
+                &create_index_name(&target_domain, &commit),
+                &hnsw,
+                &tombstones,
+            )?;
+        }
+        Commands::CrossDuplicates {
+            directory,
+            shard,
+            size,
+            threshold,
+            neighbours,
+            report,
+        } => {
+            let dirpath = Path::new(&directory);
+            let store = VectorStore::new(dirpath, size);
+            let mut shards = Vec::with_capacity(shard.len());
+            for name in shard {
+                let (domain, commit) = name
+                    .rsplit_once('@')
+                    .ok_or_else(|| format!("shard {name} is not of the form DOMAIN@COMMIT"))?;
+                let index_id = create_index_name(domain, commit);
+                let (hnsw, tombstones) = deserialize_index_with_tombstones(
+                    &mut dirpath.to_path_buf(),
+                    &index_id,
+                    &store,
+                )?;
+                shards.push((domain.to_string(), tombstones, hnsw));
+            }
+            let indexes: Vec<(&HnswIndex, &Tombstones)> = shards
+                .iter()
+                .map(|(_, tombstones, hnsw)| (hnsw, tombstones))
+                .collect();
+            let pairs = cross_index_pairs(&indexes, neighbours, threshold);
+            if report {
+                // Points of all shards are numbered one after another.
+                let offsets: Vec<usize> = indexes
+                    .iter()
+                    .scan(0, |offset, (hnsw, _)| {
+                        let start = *offset;
+                        *offset += hnsw.layer_len(0);
+                        Some(start)
+                    })
+                    .collect();
+                let global = |(shard, i): (usize, usize)| offsets[shard] + i;
+                let feature = |id: usize| {
+                    let shard = offsets.partition_point(|&offset| offset <= id) - 1;
+                    indexes[shard].0.feature(id - offsets[shard])
+                };
+                let pairs: Vec<(usize, usize)> = pairs
+                    .iter()
+                    .map(|pair| (global(pair.a), global(pair.b)))
+                    .collect();
+                let report = duplicate_report_by(feature, &pairs, DUPLICATE_REPORT_CLUSTERS);
+                println!("{}", serde_json::to_string(&report)?);
+            } else {
+                for pair in pairs {
+                    let document = |(shard, i): (usize, usize)| json!({"domain": shards[shard].0, "id": indexes[shard].0.feature(i).id()});
+                    let line = json!({
+                        "a": document(pair.a),
+                        "b": document(pair.b),
+                        "distance": pair.distance,
+                    });
+                    println!("{line}");
+                }
+            }
+        }
+        Commands::TrainingPairs {
+            directory,
+            domain,
+            commit,
+            size,
+            input,
+            output,
+            neighbours,
+            bands,
+            max_distance,
+            per_band,
+        } => {
+            let dirpath = Path::new(&directory);
+            let store = VectorStore::new(dirpath, size);
+            let index_id = create_index_name(&domain, &commit);
+            // Deleted points are left out, so that the old version of a
+            // replaced document is not paired with its new text.
+            let (hnsw, tombstones) =
+                deserialize_index_with_tombstones(&mut dirpath.to_path_buf(), &index_id, &store)?;
+
+            // The texts come from the operations the index was loaded from.
+            let mut texts = HashMap::new();
+            for line in io::BufReader::new(File::open(input)?).lines() {
+                match serde_json::from_str(&line?)? {
+                    Operation::Inserted { string, id } | Operation::Changed { string, id } => {
+                        texts.insert(id, string);
+                    }
+                    _ => {}
+                }
+            }
+
+            let bands = bands.max(1);
+            let band_width = max_distance / bands as f32;
+            let mut banded: Vec<Vec<(usize, usize, f32)>> = vec![Vec::new(); bands];
+            for (i, j, distance) in neighbour_pairs(&hnsw, &tombstones, neighbours) {
+                if distance < max_distance {
+                    let band = ((distance / band_width) as usize).min(bands - 1);
+                    banded[band].push((i, j, distance));
+                }
+            }
+
+            let mut rng = rand::thread_rng();
+            let mut output = io::BufWriter::new(File::create(output)?);
+            for band in banded {
+                for (i, j, distance) in band.choose_multiple(&mut rng, per_band) {
+                    let a = texts.get(hnsw.feature(*i).id());
+                    let b = texts.get(hnsw.feature(*j).id());
+                    if let (Some(a), Some(b)) = (a, b) {
+                        let pair = json!({"text_a": a, "text_b": b, "distance": distance});
+                        serde_json::to_writer(&mut output, &pair)?;
+                        writeln!(output)?;
+                    }
+                }
+            }
+            output.flush()?;
+        }
+        Commands::Calibrate {
+            directory,
+            domain,
+            commit,
+            size,
+            samples,
+            labels,
+            target_precision,
+        } => {
+            let dirpath = Path::new(&directory);
+            let store = VectorStore::new(dirpath, size);
+            let index_id = create_index_name(&domain, &commit);
+            let (hnsw, tombstones) =
+                deserialize_index_with_tombstones(&mut dirpath.to_path_buf(), &index_id, &store)?;
+
+            let (random, near) =
+                calibration_distances(&hnsw, &tombstones, samples, &mut rand::thread_rng());
+            println!("percentile\trandom pairs\tnearest neighbours");
+            for p in [1.0, 5.0, 10.0, 25.0, 50.0, 75.0, 90.0, 95.0, 99.0] {
+                let show = |d: Option<f32>| d.map_or("-".to_string(), |d| format!("{d:.4}"));
+                println!(
+                    "{p}\t{}\t{}",
+                    show(percentile(&random, p)),
+                    show(percentile(&near, p))
+                );
+            }
+            // Random pairs are almost never duplicates, so thresholds
+            // below their low percentiles rarely match unrelated
+            // documents.
+            if let Some(threshold) = percentile(&random, 0.1) {
+                println!("suggested upper bound for thresholds: {threshold:.4}");
+            }
+
+            if let Some(labels) = labels {
+                #[derive(Deserialize)]
+                struct LabeledPair {
+                    id_a: String,
+                    id_b: String,
+                    duplicate: bool,
+                }
+                let mut pairs = Vec::new();
+                for line in io::BufReader::new(File::open(labels)?).lines() {
+                    let pair: LabeledPair = serde_json::from_str(&line?)?;
+                    let a = find_point(&hnsw, &tombstones, &pair.id_a);
+                    let b = find_point(&hnsw, &tombstones, &pair.id_b);
+                    match (a, b) {
+                        (Some(a), Some(b)) => {
+                            pairs.push((f32::from_bits(OpenAI.distance(a, b)), pair.duplicate))
+                        }
+                        _ => {
+                            log!("skipping pair with unknown ids {} {}", pair.id_a, pair.id_b)
+                        }
+                    }
+                }
+                let mut thresholds: Vec<f32> = pairs
+                    .iter()
+                    .map(|(distance, _)| distance + f32::EPSILON)
+                    .collect();
+                thresholds.sort_by(f32::total_cmp);
+                thresholds.dedup();
+                println!("threshold\tprecision\trecall");
+                let quality = threshold_quality(&pairs, &thresholds);
+                for q in quality.iter() {
+                    println!("{:.4}\t{:.3}\t{:.3}", q.threshold, q.precision, q.recall);
+                }
+                match quality.iter().rev().find(|q| q.precision >= target_precision) {
+                    Some(q) => println!(
+                        "suggested threshold for precision {target_precision}: {:.4} (recall {:.3})",
+                        q.threshold, q.recall
+                    ),
+                    None => println!("no threshold reaches precision {target_precision}"),
+                }
+            }
+        }
+        Commands::Synth {
+            directory,
+            domain,
+            count,
+            clusters,
+            dimension,
+            spread,
+            seed,
+        } => {
+            let store = VectorStore::new(Path::new(&directory), 10000);
+            let resolved_domain = store.get_domain(&domain)?;
+            let parameters = SynthParameters {
+                size: count,
+                clusters,
+                dimension,
+                spread,
+                seed,
+            };
+            store.synthesize_domain(&resolved_domain, &parameters)?;
+            log!("generated {count} vectors in {clusters} clusters");
+        }
+        Commands::Search {
+            server,
+            key,
+            domain,
+            commit,
+            count,
+            query,
+        } => {
+            let client = Client::new(&server, Some(key_or_env(key)))?;
+            for result in client.search(&domain, &commit, &query, count).await? {
+                println!("{}\t{}", result.distance, result.id);
+            }
+        }
+        Commands::Index {
+            server,
+            key,
+            domain,
+            commit,
+            previous,
+            wait,
+        } => {
+            let client = Client::new(&server, Some(key_or_env(key)))?;
+            let task_id = client
+                .start_index(&domain, &commit, previous.as_deref())
+                .await?;
+            if wait {
+                let indexed_documents = client.wait_for_task(&task_id).await?;
+                log!("indexed {indexed_documents} documents");
+            } else {
+                println!("{task_id}");
+            }
+        }
+        Commands::Binarize {
+            directory,
+            domain,
+            size,
+        } => {
+            let store = VectorStore::new(Path::new(&directory), size);
+            let resolved_domain = store.get_domain(&domain)?;
+            let count = store.binarize_domain(&resolved_domain)?;
+            log!("binarized {count} vectors");
+        }
+        Commands::SearchBinarized {
+            directory,
+            domain,
+            size,
+            key,
+            model,
+            allow_model_mismatch,
+            count,
+            rerank,
+            query,
+            provider,
+        } => {
+            let store = VectorStore::new(Path::new(&directory), size);
+            let resolved_domain = store.get_domain(&domain)?;
+            let provider = provider.provider()?;
+            let model = resolved_domain.resolve_model(model, &provider, allow_model_mismatch)?;
+            let bits = store.load_binarized_domain(&resolved_domain)?;
+            let key = provider_key_or_env(&provider, key);
+            let embeddings = provider
+                .embeddings_for(&key, &[model.query_text(&query)], model)
+                .await?;
+            for (index, distance) in search_binarized(
+                &embeddings.embeddings[0],
+                count,
+                rerank,
+                &bits,
+                &store,
+                &resolved_domain,
+            )? {
+                println!("{distance}\t{index}");
+            }
         }
         Commands::Load {
             key,
@@ -195,33 +1342,211 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             directory,
             input,
             size,
+            model,
+            allow_model_mismatch,
+            in_memory,
+            in_memory_threshold,
+            quarantine,
+            from_quarantine,
+            invalid_embedding_policy,
+            seed,
+            previous,
+            checkpoint_interval,
+            resume,
+            embedding_cache_size,
+            provider,
         } => {
-            let path = Path::new(&input);
             let dirpath = Path::new(&directory);
-            let mut hnsw: HnswIndex = Hnsw::new(OpenAI);
+            let downloaded = input.starts_with("http://") || input.starts_with("https://");
+            let path = if downloaded {
+                download_operations(&input, dirpath).await?
+            } else {
+                PathBuf::from(input)
+            };
+            let path = path.as_path();
             let store = VectorStore::new(dirpath, size);
+            let index_id = create_index_name(&domain, &commit);
+            let checkpoint = if resume {
+                read_checkpoint(dirpath, &index_id, &store)?
+            } else {
+                None
+            };
+            let resumed = checkpoint.as_ref().map(|(_, _, c)| c.clone());
+            let resumed_lines = resumed.as_ref().map_or(0, |c| c.lines);
+            // Only the operations since the previous commit are applied
+            // to its index, instead of rebuilding from scratch.
+            let (mut hnsw, mut tombstones): (HnswIndex, Tombstones) = match (checkpoint, previous) {
+                (Some((hnsw, tombstones, checkpoint)), _) => {
+                    log!("resuming after line {}", checkpoint.lines);
+                    (hnsw, tombstones)
+                }
+                (None, Some(previous)) => {
+                    let previous_id = create_index_name(&domain, &previous);
+                    deserialize_index_with_tombstones(
+                        &mut dirpath.to_path_buf(),
+                        &previous_id,
+                        &store,
+                    )?
+                }
+                (None, None) => (new_index(seed), Tombstones::default()),
+            };
+            let mut ids = IdIndex::new(&hnsw);
             let resolved_domain = store.get_domain(&domain)?;
+            let provider = provider.provider()?;
+            let model =
+                model_for_indexing(&resolved_domain, model, &provider, allow_model_mismatch)?;
+
+            // Every operation adds at most one vector, so the number of
+            // lines bounds what this load adds to the domain.
+            let operation_count = estimate_line_count(path)?;
+            if in_memory || resolved_domain.num_vecs() + operation_count <= in_memory_threshold {
+                store.reserve_for_domain(&resolved_domain, operation_count);
+            }
+
+            // Opening the quarantine file would truncate the input
+            // before it is read.
+            if let Some(quarantine) = &quarantine {
+                if is_same_file(Path::new(quarantine), path)? {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the quarantine file can not be the input file",
+                    )
+                    .into());
+                }
+            }
+            // A resumed load keeps the records of the lines it skips,
+            // and drops those of the lines it applies again.
+            let mut quarantine = match quarantine {
+                Some(quarantine) => {
+                    let mut file = File::options()
+                        .create(true)
+                        .write(true)
+                        .truncate(false)
+                        .open(quarantine)?;
+                    let length = resumed.as_ref().map_or(0, |c| c.quarantine_length);
+                    file.set_len(file.metadata()?.len().min(length))?;
+                    file.seek(SeekFrom::End(0))?;
+                    Some(io::BufWriter::new(file))
+                }
+                None => None,
+            };
 
             let f = File::options().read(true).open(path)?;
+            let mut usage = EmbeddingUsage::default();
+
+            let mut lines_done = resumed_lines;
+            let mut lines_since_checkpoint = 0;
+            let lines = io::BufReader::new(f)
+                .lines()
+                .enumerate()
+                .skip(resumed_lines)
+                .map(|(index, line)| -> io::Result<(usize, String)> {
+                    let line = line?;
+                    if from_quarantine {
+                        let record: QuarantineRecord = serde_json::from_str(&line)?;
+                        Ok((record.line, record.operation))
+                    } else {
+                        Ok((index + 1, line))
+                    }
+                });
+            let opstream = &lines.chunks(100);
 
-            let lines = io::BufReader::new(f).lines();
-            let opstream = &lines
-                .map(|l| {
-                    let ro: io::Result<Operation> = serde_json::from_str(&l.unwrap())
-                        .map_err(|e| std::io::Error::new(ErrorKind::Other, e));
-                    ro
-                })
-                .chunks(100);
-
-            let key = key_or_env(key);
-            for structs in opstream {
-                let structs: Vec<_> = structs.collect();
-                let new_ops =
-                    operations_to_point_operations(&resolved_domain, &store, structs, &key).await?;
-                hnsw = start_indexing_from_operations(hnsw, new_ops).unwrap();
+            let key = provider_key_or_env(&provider, key);
+            let embedding_cache =
+                EmbeddingCache::new(embedding_cache_size, provider, CircuitBreaker::default());
+            for chunk in opstream {
+                let mut structs = Vec::new();
+                let mut chunk_lines = Vec::new();
+                let mut numbered = Vec::new();
+                let mut chunk_len = 0;
+                for entry in chunk {
+                    chunk_len += 1;
+                    let (line, operation) = entry?;
+                    match (serde_json::from_str(&operation), quarantine.as_mut()) {
+                        (Ok(Operation::Error { message }), Some(quarantine)) => {
+                            write_quarantine_record(quarantine, line, message, operation)?
+                        }
+                        (Ok(op), _) => {
+                            let id = op.id().map(str::to_string);
+                            numbered.push((line, op.clone()));
+                            structs.push(Ok(op));
+                            chunk_lines.push((line, operation, id));
+                        }
+                        (Err(e), Some(quarantine)) => {
+                            write_quarantine_record(quarantine, line, e.to_string(), operation)?
+                        }
+                        (Err(e), None) => return Err(e.into()),
+                    }
+                }
+                match operations_to_point_operations(
+                    &resolved_domain,
+                    &store,
+                    structs,
+                    &key,
+                    model,
+                    &embedding_cache,
+                    invalid_embedding_policy,
+                )
+                .await
+                {
+                    Ok((new_ops, rejected, chunk_usage)) => {
+                        usage += chunk_usage;
+                        for rejected in rejected {
+                            let entry = chunk_lines
+                                .iter()
+                                .find(|(_, _, id)| id.as_deref() == Some(&rejected.id));
+                            match (entry, quarantine.as_mut()) {
+                                (Some((line, operation, _)), Some(quarantine)) => {
+                                    write_quarantine_record(
+                                        quarantine,
+                                        *line,
+                                        rejected.problem.to_string(),
+                                        operation.clone(),
+                                    )?
+                                }
+                                _ => log!("skipping {}: {}", rejected.id, rejected.problem),
+                            }
+                        }
+                        resolved_domain
+                            .record_provenance(provenance_for(&commit, &numbered, &new_ops))?;
+                        apply_operations(&mut hnsw, &mut tombstones, &mut ids, new_ops);
+                    }
+                    Err(IndexError::EmbeddingError(e)) if quarantine.is_some() => {
+                        let quarantine = quarantine.as_mut().unwrap();
+                        for (line, operation, _) in chunk_lines {
+                            write_quarantine_record(quarantine, line, e.to_string(), operation)?;
+                        }
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                lines_done += chunk_len;
+                lines_since_checkpoint += chunk_len;
+                if checkpoint_interval.is_some_and(|interval| lines_since_checkpoint >= interval) {
+                    let mut quarantine_length = 0;
+                    if let Some(quarantine) = quarantine.as_mut() {
+                        quarantine.flush()?;
+                        quarantine_length = quarantine.get_ref().metadata()?.len();
+                    }
+                    write_checkpoint(
+                        dirpath,
+                        &index_id,
+                        &hnsw,
+                        &tombstones,
+                        lines_done,
+                        quarantine_length,
+                    )?;
+                    lines_since_checkpoint = 0;
+                }
             }
-            let index_id = create_index_name(&domain, &commit);
-            serialize_index(dirpath.to_path_buf(), &index_id, hnsw.clone()).unwrap();
+            if let Some(quarantine) = quarantine.as_mut() {
+                quarantine.flush()?;
+            }
+            serialize_index(dirpath.to_path_buf(), &index_id, &hnsw, &tombstones)?;
+            remove_checkpoint(dirpath, &index_id)?;
+            if downloaded {
+                std::fs::remove_file(path)?;
+            }
+            log!("{}", json!({ "model": model, "usage": usage }));
         }
     }
 
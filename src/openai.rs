@@ -1,5 +1,17 @@
 #![allow(unused, dead_code)]
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
 use lazy_static::lazy_static;
+use lru::LruCache;
 use reqwest::{header::HeaderValue, Body, Client, Method, Request, StatusCode, Url};
 use serde::{
     de::{SeqAccess, Visitor},
@@ -8,7 +20,183 @@ use serde::{
 use thiserror::Error;
 use tiktoken_rs::{cl100k_base, CoreBPE};
 
-use crate::vecmath::Embedding;
+use crate::log::log;
+use crate::vecmath::{Embedding, EMBEDDING_LENGTH};
+
+/// An embedding model, named by one of the descriptions in the model
+/// registry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Model(&'static str);
+
+impl Default for Model {
+    fn default() -> Self {
+        Model::ADA2
+    }
+}
+
+/// What is known about an embedding model.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub name: String,
+    pub alias: String,
+    pub provider: String,
+    pub dimension: usize,
+    /// Whether the provider returns unit-length vectors.
+    pub normalized: bool,
+    pub max_tokens: usize,
+    /// Template that queries are put into before they are embedded,
+    /// with `{text}` standing for the query.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_prompt: Option<String>,
+    /// Template that documents are put into before they are embedded,
+    /// with `{text}` standing for the document.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document_prompt: Option<String>,
+}
+
+impl ModelInfo {
+    fn openai(name: &str, alias: &str) -> Self {
+        ModelInfo {
+            name: name.to_string(),
+            alias: alias.to_string(),
+            provider: "openai".to_string(),
+            dimension: EMBEDDING_LENGTH,
+            normalized: true,
+            max_tokens: 8191,
+            query_prompt: None,
+            document_prompt: None,
+        }
+    }
+}
+
+fn builtin_models() -> Vec<ModelInfo> {
+    vec![
+        ModelInfo::openai("text-embedding-ada-002", "ada2"),
+        ModelInfo::openai("text-embedding-3-small", "small3"),
+    ]
+}
+
+static MODEL_REGISTRY: OnceLock<Vec<ModelInfo>> = OnceLock::new();
+
+fn registry() -> &'static [ModelInfo] {
+    MODEL_REGISTRY.get_or_init(builtin_models)
+}
+
+#[derive(Error, Debug)]
+pub enum ModelRegistryError {
+    #[error("could not read model registry: {0}")]
+    Io(#[from] io::Error),
+    #[error("could not parse model registry: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("model {name} has dimension {dimension}, but only {EMBEDDING_LENGTH} is supported")]
+    UnsupportedDimension { name: String, dimension: usize },
+    #[error("the model registry is already in use")]
+    AlreadyLoaded,
+}
+
+/// Read descriptions of embedding models from the JSON file at
+/// `path`, which holds a list of [ModelInfo]. They are added to the
+/// built-in models, and a description with the name of a built-in
+/// model replaces it. This has to happen before any model is used.
+pub fn load_model_registry(path: &Path) -> Result<(), ModelRegistryError> {
+    let descriptions: Vec<ModelInfo> =
+        serde_json::from_reader(io::BufReader::new(File::open(path)?))?;
+    let mut models = builtin_models();
+    for description in descriptions {
+        if description.dimension != EMBEDDING_LENGTH {
+            return Err(ModelRegistryError::UnsupportedDimension {
+                name: description.name,
+                dimension: description.dimension,
+            });
+        }
+        match models
+            .iter_mut()
+            .find(|model| model.name == description.name)
+        {
+            Some(model) => *model = description,
+            None => models.push(description),
+        }
+    }
+    MODEL_REGISTRY
+        .set(models)
+        .map_err(|_| ModelRegistryError::AlreadyLoaded)
+}
+
+impl Model {
+    pub const ADA2: Model = Model("text-embedding-ada-002");
+    pub const SMALL3: Model = Model("text-embedding-3-small");
+
+    pub fn name(&self) -> &'static str {
+        self.0
+    }
+
+    /// All models in the registry.
+    pub fn all() -> Vec<Model> {
+        registry()
+            .iter()
+            .map(|info| Model(info.name.as_str()))
+            .collect()
+    }
+
+    pub fn info(&self) -> &'static ModelInfo {
+        registry()
+            .iter()
+            .find(|info| info.name == self.0)
+            .expect("models are only made for registered names")
+    }
+
+    /// The text to embed for the query `text`.
+    pub fn query_text(&self, text: &str) -> String {
+        apply_prompt(self.info().query_prompt.as_deref(), text)
+    }
+
+    /// The text to embed for the document `text`.
+    pub fn document_text(&self, text: &str) -> String {
+        apply_prompt(self.info().document_prompt.as_deref(), text)
+    }
+}
+
+fn apply_prompt(template: Option<&str>, text: &str) -> String {
+    match template {
+        Some(template) => template.replace("{text}", text),
+        None => text.to_string(),
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.info().alias)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("unknown embedding model: {0}")]
+pub struct UnknownModelError(String);
+
+impl FromStr for Model {
+    type Err = UnknownModelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        registry()
+            .iter()
+            .find(|info| info.alias == s || info.name == s)
+            .map(|info| Model(info.name.as_str()))
+            .ok_or_else(|| UnknownModelError(s.to_string()))
+    }
+}
+
+impl Serialize for Model {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Model {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
 
 #[derive(Serialize)]
 struct EmbeddingRequest<'a> {
@@ -69,10 +257,27 @@ impl<'de> Visitor<'de> for SingleEmbeddingVisitor {
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct EmbeddingUsage {
-    prompt_tokens: usize,
-    total_tokens: usize,
+/// The tokens a provider charged for a request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl std::ops::AddAssign for EmbeddingUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// Embeddings along with what the provider reported about the
+/// request: the exact model version and the tokens used.
+#[derive(Clone, Debug)]
+pub struct Embeddings {
+    pub embeddings: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
 }
 
 #[derive(Error, Debug)]
@@ -84,6 +289,30 @@ pub enum EmbeddingError {
 
     #[error("error while parsing json: {0:?}")]
     BadJson(#[from] serde_json::Error),
+    #[error("embedding provider is unavailable after repeated failures")]
+    CircuitOpen,
+    #[error("embedding provider returned a vector of dimension {0} instead of 1536")]
+    WrongDimension(usize),
+    #[error("api key contains characters that can not be sent in a header")]
+    InvalidApiKey,
+    #[error("embedding provider returned {actual} embeddings for {expected} texts")]
+    WrongCount { expected: usize, actual: usize },
+    #[error("embedding provider returned no embedding for text {0}")]
+    MissingIndex(usize),
+}
+
+impl EmbeddingError {
+    /// Whether this error says something about the health of the
+    /// provider, rather than about the request.
+    fn is_provider_failure(&self) -> bool {
+        match self {
+            EmbeddingError::BadStatus(status, _) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            EmbeddingError::CircuitOpen | EmbeddingError::InvalidApiKey => false,
+            _ => true,
+        }
+    }
 }
 
 lazy_static! {
@@ -94,13 +323,12 @@ fn tokens_for(s: &str) -> Vec<usize> {
     ENCODER.encode_with_special_tokens(s)
 }
 
-const MAX_TOKEN_COUNT: usize = 8191;
-fn truncated_tokens_for(s: &str) -> Vec<usize> {
+fn truncated_tokens_for(s: &str, max_tokens: usize) -> Vec<usize> {
     let mut tokens = tokens_for(s);
-    if tokens.len() > MAX_TOKEN_COUNT {
-        tokens.truncate(MAX_TOKEN_COUNT);
+    if tokens.len() > max_tokens {
+        tokens.truncate(max_tokens);
         let decoded = ENCODER.decode(tokens.clone()).unwrap();
-        eprintln!("truncating to {decoded}");
+        log!("truncating to {decoded}");
     }
 
     tokens
@@ -109,30 +337,53 @@ fn truncated_tokens_for(s: &str) -> Vec<usize> {
 pub async fn embeddings_for(
     api_key: &str,
     strings: &[String],
+    model: Model,
 ) -> Result<Vec<Embedding>, EmbeddingError> {
+    Ok(embeddings_with_usage_for(api_key, strings, model)
+        .await?
+        .embeddings)
+}
+
+pub async fn embeddings_with_usage_for(
+    api_key: &str,
+    strings: &[String],
+    model: Model,
+) -> Result<Embeddings, EmbeddingError> {
     lazy_static! {
         static ref ENDPOINT: Url = Url::parse("https://api.openai.com/v1/embeddings").unwrap();
-        static ref CLIENT: Client = Client::new();
     }
 
-    let token_lists: Vec<_> = strings.iter().map(|s| truncated_tokens_for(s)).collect();
+    let authorization = HeaderValue::from_str(&format!("Bearer {api_key}"))
+        .map_err(|_| EmbeddingError::InvalidApiKey)?;
+    openai_style_embeddings_for(
+        ENDPOINT.clone(),
+        "Authorization",
+        authorization,
+        strings,
+        model,
+    )
+    .await
+}
 
-    let mut req = Request::new(Method::POST, ENDPOINT.clone());
+/// Post `body` as json to `url`, returning the response body if the
+/// request succeeded.
+async fn post_json(
+    url: Url,
+    auth: Option<(&'static str, HeaderValue)>,
+    body: Vec<u8>,
+) -> Result<Vec<u8>, EmbeddingError> {
+    lazy_static! {
+        static ref CLIENT: Client = Client::new();
+    }
+
+    let mut req = Request::new(Method::POST, url);
     let headers = req.headers_mut();
     headers.insert("Content-Type", HeaderValue::from_static("application/json"));
-    headers.insert(
-        "Authorization",
-        HeaderValue::from_str(&format!("Bearer {api_key}")).unwrap(),
-    );
-
-    let body = EmbeddingRequest {
-        model: "text-embedding-ada-002",
-        input: &token_lists,
-        user: None,
-    };
-    let body_vec = serde_json::to_vec(&body).unwrap();
-    let body: Body = body_vec.into();
+    if let Some((name, value)) = auth {
+        headers.insert(name, value);
+    }
 
+    let body: Body = body.into();
     *req.body_mut() = Some(body); // once told me the world is gonna roll me
 
     let response = CLIENT.execute(req).await?;
@@ -142,11 +393,670 @@ pub async fn embeddings_for(
         let body = String::from_utf8_lossy(&response_bytes).to_string();
         return Err(EmbeddingError::BadStatus(status, body));
     }
+
+    Ok(response_bytes.to_vec())
+}
+
+/// Embeddings from an endpoint speaking the OpenAI embeddings API,
+/// which Azure OpenAI does as well.
+async fn openai_style_embeddings_for(
+    url: Url,
+    auth_header: &'static str,
+    auth: HeaderValue,
+    strings: &[String],
+    model: Model,
+) -> Result<Embeddings, EmbeddingError> {
+    let max_tokens = model.info().max_tokens;
+    let token_lists: Vec<_> = strings
+        .iter()
+        .map(|s| truncated_tokens_for(s, max_tokens))
+        .collect();
+
+    let body = EmbeddingRequest {
+        model: model.name(),
+        input: &token_lists,
+        user: None,
+    };
+    let body_vec = serde_json::to_vec(&body).unwrap();
+    let response_bytes = post_json(url, Some((auth_header, auth)), body_vec).await?;
     let response: EmbeddingResponse = serde_json::from_slice(&response_bytes)?;
-    let mut result = Vec::with_capacity(strings.len());
-    for embedding in response.data {
-        result.push(embedding.embedding);
+
+    Ok(Embeddings {
+        embeddings: ordered_embeddings(response.data, strings.len())?,
+        model: response.model,
+        usage: response.usage,
+    })
+}
+
+/// The embeddings of a response in the order of the texts they belong
+/// to, which the response gives by `index` rather than by position.
+fn ordered_embeddings(
+    mut data: Vec<EmbeddingData>,
+    expected: usize,
+) -> Result<Vec<Embedding>, EmbeddingError> {
+    if data.len() != expected {
+        return Err(EmbeddingError::WrongCount {
+            expected,
+            actual: data.len(),
+        });
+    }
+    data.sort_by_key(|embedding| embedding.index);
+    // With as many embeddings as texts, a duplicate index leaves out
+    // some other one.
+    if let Some(missing) = (0..expected).find(|&i| data[i].index != i) {
+        return Err(EmbeddingError::MissingIndex(missing));
+    }
+    Ok(data
+        .into_iter()
+        .map(|embedding| embedding.embedding)
+        .collect())
+}
+
+#[derive(Serialize)]
+struct LocalEmbeddingRequest<'a> {
+    inputs: &'a [String],
+    truncate: bool,
+}
+
+/// Parse the response of a text-embeddings-inference server, which
+/// is a plain list of vectors in the order of the `expected` texts.
+fn parse_local_embeddings(
+    response_bytes: &[u8],
+    expected: usize,
+) -> Result<Vec<Embedding>, EmbeddingError> {
+    let vectors: Vec<Vec<f32>> = serde_json::from_slice(response_bytes)?;
+    if vectors.len() != expected {
+        return Err(EmbeddingError::WrongCount {
+            expected,
+            actual: vectors.len(),
+        });
+    }
+    let mut result = Vec::with_capacity(vectors.len());
+    for vector in vectors {
+        let embedding: Embedding = vector
+            .as_slice()
+            .try_into()
+            .map_err(|_| EmbeddingError::WrongDimension(vector.len()))?;
+        result.push(embedding);
     }
 
     Ok(result)
 }
+
+/// Where embeddings are obtained from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum EmbeddingProvider {
+    #[default]
+    OpenAI,
+    /// An Azure OpenAI deployment. The model is decided by the
+    /// deployment rather than by the request.
+    AzureOpenAI { url: Url },
+    /// An inference server speaking the text-embeddings-inference
+    /// API, which needs no api key.
+    Local { url: Url },
+}
+
+impl EmbeddingProvider {
+    /// An Azure OpenAI provider for `deployment` of the resource at
+    /// `endpoint` (like `https://my-resource.openai.azure.com`).
+    pub fn azure(
+        endpoint: &str,
+        deployment: &str,
+        api_version: &str,
+    ) -> Result<Self, url::ParseError> {
+        let mut url = Url::parse(endpoint)?.join(&format!(
+            "openai/deployments/{}/embeddings",
+            urlencoding::encode(deployment)
+        ))?;
+        url.query_pairs_mut()
+            .append_pair("api-version", api_version);
+        Ok(EmbeddingProvider::AzureOpenAI { url })
+    }
+
+    /// A provider for the inference server at `endpoint` (like
+    /// `http://localhost:8081`).
+    pub fn local(endpoint: &str) -> Result<Self, url::ParseError> {
+        let url = Url::parse(endpoint)?.join("embed")?;
+        Ok(EmbeddingProvider::Local { url })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmbeddingProvider::OpenAI => "openai",
+            EmbeddingProvider::AzureOpenAI { .. } => "azure",
+            EmbeddingProvider::Local { .. } => "local",
+        }
+    }
+
+    /// The name this provider knows `model` by. An Azure deployment
+    /// decides its model itself and a local server serves a single
+    /// model, so those are named by their url.
+    pub fn model_name(&self, model: Model) -> String {
+        match self {
+            EmbeddingProvider::OpenAI => model.name().to_string(),
+            EmbeddingProvider::AzureOpenAI { url } | EmbeddingProvider::Local { url } => {
+                url[..url::Position::AfterPath].to_string()
+            }
+        }
+    }
+
+    /// Whether requests to this provider need an api key.
+    pub fn needs_key(&self) -> bool {
+        !matches!(self, EmbeddingProvider::Local { .. })
+    }
+
+    /// Embed `strings`. A local server reports no usage, so its
+    /// responses count no tokens.
+    pub async fn embeddings_for(
+        &self,
+        api_key: &str,
+        strings: &[String],
+        model: Model,
+    ) -> Result<Embeddings, EmbeddingError> {
+        match self {
+            EmbeddingProvider::OpenAI => embeddings_with_usage_for(api_key, strings, model).await,
+            EmbeddingProvider::AzureOpenAI { url } => {
+                let key =
+                    HeaderValue::from_str(api_key).map_err(|_| EmbeddingError::InvalidApiKey)?;
+                openai_style_embeddings_for(url.clone(), "api-key", key, strings, model).await
+            }
+            EmbeddingProvider::Local { url } => {
+                let body = LocalEmbeddingRequest {
+                    inputs: strings,
+                    truncate: true,
+                };
+                let body_vec = serde_json::to_vec(&body).unwrap();
+                let response_bytes = post_json(url.clone(), None, body_vec).await?;
+                Ok(Embeddings {
+                    embeddings: parse_local_embeddings(&response_bytes, strings.len())?,
+                    model: model.name().to_string(),
+                    usage: EmbeddingUsage::default(),
+                })
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderHealth {
+    Healthy,
+    Degraded,
+    Unavailable,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: usize,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+/// Stops calls to the embedding provider after `failure_threshold`
+/// consecutive failures. Once `reset_timeout` has passed a single probe
+/// is let through, which closes the breaker again if it succeeds.
+///
+/// A breaker with a threshold of 0 never opens.
+#[derive(Default)]
+pub struct CircuitBreaker {
+    failure_threshold: usize,
+    reset_timeout: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: usize, reset_timeout: Duration) -> Self {
+        Self {
+            failure_threshold,
+            reset_timeout,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub fn reset_timeout(&self) -> Duration {
+        self.reset_timeout
+    }
+
+    fn acquire(&self) -> Result<BreakerPermit<'_>, EmbeddingError> {
+        let mut state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => Ok(BreakerPermit {
+                breaker: self,
+                probe: false,
+            }),
+            Some(opened_at) if !state.probing && opened_at.elapsed() >= self.reset_timeout => {
+                state.probing = true;
+                Ok(BreakerPermit {
+                    breaker: self,
+                    probe: true,
+                })
+            }
+            Some(_) => Err(EmbeddingError::CircuitOpen),
+        }
+    }
+
+    fn record<T>(&self, result: &Result<T, EmbeddingError>) {
+        let mut state = self.state.lock().unwrap();
+        state.probing = false;
+        match result {
+            Err(e) if e.is_provider_failure() => {
+                state.consecutive_failures += 1;
+                if self.failure_threshold != 0
+                    && state.consecutive_failures >= self.failure_threshold
+                {
+                    state.opened_at = Some(Instant::now());
+                }
+            }
+            _ => {
+                state.consecutive_failures = 0;
+                state.opened_at = None;
+            }
+        }
+    }
+
+    pub fn health(&self) -> ProviderHealth {
+        let state = self.state.lock().unwrap();
+        if state.opened_at.is_some() {
+            ProviderHealth::Unavailable
+        } else if state.consecutive_failures != 0 {
+            ProviderHealth::Degraded
+        } else {
+            ProviderHealth::Healthy
+        }
+    }
+}
+
+/// Permission to call the embedding provider, whose outcome should be
+/// recorded with [`BreakerPermit::record`]. A probe dropped before
+/// that, like when its caller is cancelled, lets the next call probe
+/// instead of keeping the breaker open.
+struct BreakerPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+}
+
+impl BreakerPermit<'_> {
+    fn record<T>(mut self, result: &Result<T, EmbeddingError>) {
+        self.probe = false;
+        self.breaker.record(result);
+    }
+}
+
+impl Drop for BreakerPermit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.state.lock().unwrap().probing = false;
+        }
+    }
+}
+
+/// A cache of embeddings, addressed by the model and a hash of the
+/// embedded text, so that the same text is not embedded twice.
+///
+/// A cache with a capacity of 0 caches nothing.
+pub struct EmbeddingCache {
+    // Keyed on the whole text, so that texts can never be mistaken
+    // for one another.
+    cache: Option<Mutex<LruCache<(Model, String), Embedding>>>,
+    provider: EmbeddingProvider,
+    breaker: CircuitBreaker,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    prompt_tokens: AtomicUsize,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmbeddingCacheStatistics {
+    entries: usize,
+    hits: usize,
+    misses: usize,
+    prompt_tokens: usize,
+}
+
+impl EmbeddingCache {
+    pub fn new(capacity: usize, provider: EmbeddingProvider, breaker: CircuitBreaker) -> Self {
+        Self {
+            cache: NonZeroUsize::new(capacity).map(|c| Mutex::new(LruCache::new(c))),
+            provider,
+            breaker,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            prompt_tokens: AtomicUsize::new(0),
+        }
+    }
+
+    /// Embed `strings`, calling the provider only for those not in the
+    /// cache. The usage is that of the provider call, if any.
+    pub async fn embeddings_for(
+        &self,
+        api_key: &str,
+        strings: &[String],
+        model: Model,
+    ) -> Result<Embeddings, EmbeddingError> {
+        if self.cache.is_none() {
+            return self.provider_embeddings_for(api_key, strings, model).await;
+        }
+
+        let mut result = self.cached(strings, model);
+        let missing: Vec<usize> = (0..strings.len())
+            .filter(|&i| result[i].is_none())
+            .collect();
+
+        let mut model_version = model.name().to_string();
+        let mut usage = EmbeddingUsage::default();
+        if !missing.is_empty() {
+            let missing_strings: Vec<String> =
+                missing.iter().map(|&i| strings[i].clone()).collect();
+            let embeddings = self
+                .provider_embeddings_for(api_key, &missing_strings, model)
+                .await?;
+            model_version = embeddings.model;
+            usage = embeddings.usage;
+            self.insert(&missing_strings, model, &embeddings.embeddings);
+            for (i, embedding) in missing.into_iter().zip(embeddings.embeddings) {
+                result[i] = Some(embedding);
+            }
+        }
+
+        Ok(Embeddings {
+            embeddings: result.into_iter().map(|e| e.unwrap()).collect(),
+            model: model_version,
+            usage,
+        })
+    }
+
+    /// The cached embeddings of `strings`, counting hits and misses.
+    fn cached(&self, strings: &[String], model: Model) -> Vec<Option<Embedding>> {
+        let Some(cache) = &self.cache else {
+            return vec![None; strings.len()];
+        };
+        let result: Vec<Option<Embedding>> = {
+            let mut cache = cache.lock().unwrap();
+            strings
+                .iter()
+                .map(|s| cache.get(&(model, s.clone())).copied())
+                .collect()
+        };
+        let hits = result.iter().filter(|e| e.is_some()).count();
+        self.hits.fetch_add(hits, Ordering::Relaxed);
+        self.misses
+            .fetch_add(strings.len() - hits, Ordering::Relaxed);
+        result
+    }
+
+    fn insert(&self, strings: &[String], model: Model, embeddings: &[Embedding]) {
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            for (s, embedding) in strings.iter().zip(embeddings) {
+                cache.put((model, s.clone()), *embedding);
+            }
+        }
+    }
+
+    async fn provider_embeddings_for(
+        &self,
+        api_key: &str,
+        strings: &[String],
+        model: Model,
+    ) -> Result<Embeddings, EmbeddingError> {
+        let permit = self.breaker.acquire()?;
+        let result = self
+            .provider
+            .embeddings_for(api_key, strings, model)
+            .await
+            .and_then(|embeddings| expect_count(embeddings, strings.len()));
+        permit.record(&result);
+        if let Ok(embeddings) = &result {
+            self.prompt_tokens
+                .fetch_add(embeddings.usage.prompt_tokens, Ordering::Relaxed);
+        }
+        result
+    }
+
+    pub fn provider(&self) -> &EmbeddingProvider {
+        &self.provider
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    pub fn statistics(&self) -> EmbeddingCacheStatistics {
+        EmbeddingCacheStatistics {
+            entries: self
+                .cache
+                .as_ref()
+                .map(|c| c.lock().unwrap().len())
+                .unwrap_or(0),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            prompt_tokens: self.prompt_tokens.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Check that a response holds an embedding for each of `expected`
+/// texts, as every caller indexes into them.
+fn expect_count(embeddings: Embeddings, expected: usize) -> Result<Embeddings, EmbeddingError> {
+    let actual = embeddings.embeddings.len();
+    if actual == expected {
+        Ok(embeddings)
+    } else {
+        Err(EmbeddingError::WrongCount { expected, actual })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unavailable() -> Result<(), EmbeddingError> {
+        Err(EmbeddingError::BadStatus(
+            StatusCode::SERVICE_UNAVAILABLE,
+            String::new(),
+        ))
+    }
+
+    #[test]
+    fn models_are_named_by_name_or_alias() {
+        assert_eq!(Model::SMALL3, "small3".parse().unwrap());
+        assert_eq!(Model::SMALL3, "text-embedding-3-small".parse().unwrap());
+        assert!("mxbai".parse::<Model>().is_err());
+        assert_eq!(
+            "\"text-embedding-ada-002\"",
+            serde_json::to_string(&Model::ADA2).unwrap()
+        );
+        assert_eq!(
+            Model::ADA2,
+            serde_json::from_str("\"text-embedding-ada-002\"").unwrap()
+        );
+        assert_eq!("ada2", Model::ADA2.to_string());
+    }
+
+    #[test]
+    fn prompts_surround_the_text() {
+        assert_eq!("query: cats", apply_prompt(Some("query: {text}"), "cats"));
+        assert_eq!("cats", apply_prompt(None, "cats"));
+        assert_eq!("cats", Model::ADA2.document_text("cats"));
+    }
+
+    #[test]
+    fn breaker_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(3600));
+        breaker.record(&Err::<(), _>(EmbeddingError::BadStatus(
+            StatusCode::BAD_REQUEST,
+            String::new(),
+        )));
+        assert_eq!(ProviderHealth::Healthy, breaker.health());
+        breaker.record(&unavailable());
+        assert_eq!(ProviderHealth::Degraded, breaker.health());
+        assert!(breaker.acquire().is_ok());
+        breaker.record(&unavailable());
+        assert_eq!(ProviderHealth::Unavailable, breaker.health());
+        assert!(matches!(
+            breaker.acquire(),
+            Err(EmbeddingError::CircuitOpen)
+        ));
+    }
+
+    #[test]
+    fn breaker_lets_one_probe_through() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record(&unavailable());
+        let probe = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_err());
+        probe.record(&Ok(()));
+        assert_eq!(ProviderHealth::Healthy, breaker.health());
+        assert!(breaker.acquire().is_ok());
+    }
+
+    #[test]
+    fn dropped_probe_lets_another_through() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record(&unavailable());
+        let probe = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_err());
+        drop(probe);
+        assert_eq!(ProviderHealth::Unavailable, breaker.health());
+        assert!(breaker.acquire().is_ok());
+    }
+
+    #[test]
+    fn disabled_breaker_never_opens() {
+        let breaker = CircuitBreaker::default();
+        for _ in 0..10 {
+            breaker.record(&unavailable());
+        }
+        assert!(breaker.acquire().is_ok());
+    }
+
+    #[test]
+    fn embedding_cache_hits_misses_and_evicts() {
+        let cache = EmbeddingCache::new(2, EmbeddingProvider::OpenAI, CircuitBreaker::default());
+        let strings = |s: &[&str]| -> Vec<String> { s.iter().map(|s| s.to_string()).collect() };
+        let mut a = [0.0; 1536];
+        a[0] = 1.0;
+        let mut b = [0.0; 1536];
+        b[1] = 1.0;
+        cache.insert(&strings(&["a", "b"]), Model::ADA2, &[a, b]);
+
+        assert_eq!(
+            vec![Some(a), None],
+            cache.cached(&strings(&["a", "c"]), Model::ADA2)
+        );
+        assert_eq!(vec![None], cache.cached(&strings(&["a"]), Model::SMALL3));
+        let statistics = cache.statistics();
+        assert_eq!(
+            (2, 1, 2),
+            (statistics.entries, statistics.hits, statistics.misses)
+        );
+
+        // "b" was used least recently, so it makes room for "c".
+        cache.insert(&strings(&["c"]), Model::ADA2, &[b]);
+        assert_eq!(
+            vec![None, Some(a), Some(b)],
+            cache.cached(&strings(&["b", "a", "c"]), Model::ADA2)
+        );
+    }
+
+    #[test]
+    fn short_responses_are_rejected() {
+        let embeddings = |count| Embeddings {
+            embeddings: vec![[0.0; 1536]; count],
+            model: "model".to_string(),
+            usage: EmbeddingUsage::default(),
+        };
+        assert!(expect_count(embeddings(2), 2).is_ok());
+        assert!(matches!(
+            expect_count(embeddings(0), 1),
+            Err(EmbeddingError::WrongCount {
+                expected: 1,
+                actual: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn provider_urls() {
+        let azure =
+            EmbeddingProvider::azure("https://example.openai.azure.com", "ada", "2023-05-15")
+                .unwrap();
+        assert_eq!(
+            azure,
+            EmbeddingProvider::AzureOpenAI {
+                url: Url::parse(
+                    "https://example.openai.azure.com/openai/deployments/ada/embeddings?api-version=2023-05-15"
+                )
+                .unwrap()
+            }
+        );
+        let local = EmbeddingProvider::local("http://localhost:8081").unwrap();
+        assert_eq!(
+            local,
+            EmbeddingProvider::Local {
+                url: Url::parse("http://localhost:8081/embed").unwrap()
+            }
+        );
+        assert!(!local.needs_key());
+        assert_eq!(
+            "https://example.openai.azure.com/openai/deployments/ada/embeddings",
+            azure.model_name(Model::ADA2)
+        );
+        assert_eq!(
+            "text-embedding-ada-002",
+            EmbeddingProvider::OpenAI.model_name(Model::ADA2)
+        );
+    }
+
+    #[test]
+    fn local_embeddings_must_have_the_right_dimension() {
+        let vector = vec![0.5_f32; 1536];
+        let response = serde_json::to_vec(&vec![vector.clone(), vector]).unwrap();
+        let embeddings = parse_local_embeddings(&response, 2).unwrap();
+        assert_eq!(2, embeddings.len());
+        assert_eq!(0.5, embeddings[1][1535]);
+        assert!(matches!(
+            parse_local_embeddings(&response, 3),
+            Err(EmbeddingError::WrongCount {
+                expected: 3,
+                actual: 2
+            })
+        ));
+
+        let response = serde_json::to_vec(&vec![vec![0.5_f32; 384]]).unwrap();
+        assert!(matches!(
+            parse_local_embeddings(&response, 1),
+            Err(EmbeddingError::WrongDimension(384))
+        ));
+    }
+
+    #[test]
+    fn openai_style_embeddings_are_ordered_by_index() {
+        let data = |indexes: &[usize]| -> Vec<EmbeddingData> {
+            indexes
+                .iter()
+                .map(|&index| {
+                    let mut embedding = [0.0; 1536];
+                    embedding[index] = 1.0;
+                    EmbeddingData {
+                        object: "embedding".to_string(),
+                        index,
+                        embedding,
+                    }
+                })
+                .collect()
+        };
+        let embeddings = ordered_embeddings(data(&[1, 0, 2]), 3).unwrap();
+        for (i, embedding) in embeddings.iter().enumerate() {
+            assert_eq!(1.0, embedding[i]);
+        }
+        assert!(matches!(
+            ordered_embeddings(data(&[0, 1]), 3),
+            Err(EmbeddingError::WrongCount { .. })
+        ));
+        assert!(matches!(
+            ordered_embeddings(data(&[0, 0, 2]), 3),
+            Err(EmbeddingError::MissingIndex(1))
+        ));
+    }
+}
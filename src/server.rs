@@ -1,8 +1,10 @@
 #![allow(unused, dead_code)]
 use bytes::Bytes;
+use clap::ValueEnum;
 use futures::StreamExt;
 use futures::TryStreamExt;
 use hnsw::Hnsw;
+use hyper::header::{self, HeaderValue};
 use hyper::HeaderMap;
 use hyper::StatusCode;
 use hyper::{
@@ -10,6 +12,7 @@ use hyper::{
     Body, Method, Request, Response, Server, Uri,
 };
 use lazy_static::lazy_static;
+use lru::LruCache;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use regex::Regex;
@@ -18,36 +21,65 @@ use serde::{self, Deserialize};
 use serde_json::json;
 use std::collections::HashSet;
 use std::string;
+use std::{
+    collections::hash_map::DefaultHasher,
+    future,
+    hash::{Hash, Hasher},
+    io::{self, ErrorKind, SeekFrom},
+    num::NonZeroUsize,
+    sync::atomic::{self, AtomicUsize},
+    time::{Duration, Instant},
+};
 use std::{
     collections::HashMap,
     convert::Infallible,
     net::{IpAddr, Ipv6Addr, SocketAddr},
     path::PathBuf,
-    sync::Arc,
-};
-use std::{
-    future,
-    io::{self, ErrorKind},
+    sync::{Arc, OnceLock},
 };
 use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::sync::Semaphore;
 use tokio::task;
 use tokio::{io::AsyncBufReadExt, sync::RwLock};
 use tokio_stream::{wrappers::LinesStream, Stream};
-use tokio_util::io::StreamReader;
+use tokio_util::io::{ReaderStream, StreamReader};
+use urlencoding::{decode, encode};
 
+use crate::indexer::apply_operations;
 use crate::indexer::create_index_name;
-use crate::indexer::deserialize_index;
+use crate::indexer::dedup_by_id;
+use crate::indexer::deserialize_index_with_tombstones;
+use crate::indexer::duplicate_report;
+use crate::indexer::estimate_recall;
+use crate::indexer::find_point;
+use crate::indexer::list_index_metadata;
+use crate::indexer::model_for_indexing;
+use crate::indexer::new_index;
 use crate::indexer::operations_to_point_operations;
-use crate::indexer::search;
+use crate::indexer::provenance_for;
+use crate::indexer::search_filtered;
+use crate::indexer::search_live;
+use crate::indexer::search_live_with_statistics;
 use crate::indexer::serialize_index;
-use crate::indexer::IndexError;
+use crate::indexer::within_distance;
 use crate::indexer::Point;
 use crate::indexer::PointOperation;
 use crate::indexer::SearchError;
-use crate::indexer::{start_indexing_from_operations, HnswIndex, IndexIdentifier, OpenAI};
-use crate::openai::{embeddings_for, EmbeddingError};
-use crate::vectors::VectorStore;
+use crate::indexer::SearchStatistics;
+use crate::indexer::{HnswIndex, IdIndex, IndexIdentifier, OpenAI, Tombstones};
+use crate::indexer::{IdFilter, IndexError, InvalidEmbeddingPolicy, RejectedEmbedding};
+use crate::log::{current_trace_id, log, TaskContext, TASK_CONTEXT, TRACE_ID};
+use crate::openai::{
+    EmbeddingCache, EmbeddingCacheStatistics, EmbeddingError, Model, ProviderHealth,
+    UnknownModelError,
+};
+use crate::vecmath::normalized_weighted_sum;
+use crate::vectors::{
+    DomainMetrics, ModelMismatchError, Provenance, VectorStore, VectorStoreStatistics,
+};
 
 #[derive(Clone, Deserialize, Debug)]
 #[serde(tag = "op")]
@@ -58,6 +90,17 @@ pub enum Operation {
     Error { message: String },
 }
 
+impl Operation {
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Operation::Inserted { id, .. }
+            | Operation::Changed { id, .. }
+            | Operation::Deleted { id } => Some(id),
+            Operation::Error { .. } => None,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct IndexRequest {
     domain: String,
@@ -72,11 +115,23 @@ enum ResourceSpec {
         domain: String,
         commit: String,
         count: usize,
+        model: Option<Model>,
+        allow_model_mismatch: bool,
+        dedup: bool,
+        explain: bool,
+        max_distance: Option<f32>,
+        preview: bool,
+        provenance: bool,
+        filter: Option<IdFilter>,
     },
     StartIndex {
         domain: String,
         commit: String,
         previous: Option<String>,
+        model: Option<Model>,
+        allow_model_mismatch: bool,
+        seed: Option<u64>,
+        recall: bool,
     },
     AssignIndex {
         domain: String,
@@ -85,19 +140,44 @@ enum ResourceSpec {
     },
     CheckTask {
         task_id: String,
+        wait: bool,
     },
     Similar {
         domain: String,
         commit: String,
         id: String,
         count: usize,
+        dedup: bool,
+        max_distance: Option<f32>,
     },
     DuplicateCandidates {
         domain: String,
         commit: String,
         threshold: f32,
+        report: bool,
+    },
+    Recommend {
+        domain: String,
+        commit: String,
+        count: usize,
+        max_distance: Option<f32>,
+    },
+    Embed {
+        domain: Option<String>,
+        model: Option<Model>,
+        allow_model_mismatch: bool,
     },
     GetStatistics,
+    GetModels,
+    File {
+        name: String,
+    },
+    ListFiles {
+        domain: Option<String>,
+    },
+    ListIndexes {
+        domain: Option<String>,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -108,6 +188,48 @@ enum SpecParseError {
     NoTaskId,
     #[error("No commit id or domain id given")]
     NoCommitIdOrDomain,
+    #[error("{0}")]
+    UnknownModel(#[from] UnknownModelError),
+    #[error("Invalid max_distance: {0}")]
+    InvalidMaxDistance(String),
+    #[error("Invalid seed: {0}")]
+    InvalidSeed(String),
+    #[error("Invalid id filter: {0}")]
+    InvalidFilter(String),
+}
+
+fn query_model(query: &HashMap<String, String>) -> Result<Option<Model>, SpecParseError> {
+    Ok(query.get("model").map(|v| v.parse::<Model>()).transpose()?)
+}
+
+fn query_max_distance(query: &HashMap<String, String>) -> Result<Option<f32>, SpecParseError> {
+    query
+        .get("max_distance")
+        .map(|v| {
+            v.parse::<f32>()
+                .map_err(|_| SpecParseError::InvalidMaxDistance(v.to_string()))
+        })
+        .transpose()
+}
+
+/// An allowlist or denylist of ids, given as a json list in the
+/// `allow` or `deny` parameter.
+fn query_id_filter(query: &HashMap<String, String>) -> Result<Option<IdFilter>, SpecParseError> {
+    let parse = |v: &String| {
+        serde_json::from_str(v).map_err(|e| SpecParseError::InvalidFilter(e.to_string()))
+    };
+    match (query.get("allow"), query.get("deny")) {
+        (Some(_), Some(_)) => Err(SpecParseError::InvalidFilter(
+            "allow and deny can not be combined".to_string(),
+        )),
+        (Some(allow), None) => Ok(Some(IdFilter::Allow(parse(allow)?))),
+        (None, Some(deny)) => Ok(Some(IdFilter::Deny(parse(deny)?))),
+        (None, None) => Ok(None),
+    }
+}
+
+fn query_flag(query: &HashMap<String, String>, key: &str) -> bool {
+    query.get(key).map(|v| v == "true").unwrap_or(false)
 }
 
 fn query_map(uri: &Uri) -> HashMap<String, String> {
@@ -142,6 +264,10 @@ fn get_header_value(header: &HeaderMap, key: &str) -> Result<String, HeaderError
     }
 }
 
+/// Prefix of the versioned API paths. Unprefixed paths are kept as
+/// aliases of the current version.
+const API_VERSION_PREFIX: &str = "/v1";
+
 fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
     lazy_static! {
         static ref RE_INDEX: Regex = Regex::new(r"^/index(/?)$").unwrap();
@@ -150,20 +276,45 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
         static ref RE_SEARCH: Regex = Regex::new(r"^/search(/?)$").unwrap();
         static ref RE_SIMILAR: Regex = Regex::new(r"^/similar(/?)$").unwrap();
         static ref RE_DUPLICATES: Regex = Regex::new(r"^/duplicates(/?)$").unwrap();
+        static ref RE_RECOMMEND: Regex = Regex::new(r"^/recommend(/?)$").unwrap();
+        static ref RE_EMBED: Regex = Regex::new(r"^/embed(/?)$").unwrap();
         static ref RE_STATISTICS: Regex = Regex::new(r"^/statistics$").unwrap();
+        static ref RE_MODELS: Regex = Regex::new(r"^/models(/?)$").unwrap();
+        static ref RE_INDEXES: Regex = Regex::new(r"^/indexes(/?)$").unwrap();
+        static ref RE_FILES: Regex = Regex::new(r"^/files(/?)$").unwrap();
+        static ref RE_FILE: Regex =
+            Regex::new(r"^/files/([\w%.@-]+\.(?:vecs|hnsw|deleted|meta))$").unwrap();
     }
     let path = uri.path();
+    let path = path
+        .strip_prefix(API_VERSION_PREFIX)
+        .filter(|p| p.starts_with('/'))
+        .unwrap_or(path);
 
     if RE_INDEX.is_match(path) {
-        let query = dbg!(query_map(uri));
+        let query = query_map(uri);
         let commit = query.get("commit").map(|v| v.to_string());
         let domain = query.get("domain").map(|v| v.to_string());
         let previous = query.get("previous").map(|v| v.to_string());
+        let model = query_model(&query)?;
+        let allow_model_mismatch = query_flag(&query, "allow_model_mismatch");
+        let seed = query
+            .get("seed")
+            .map(|v| {
+                v.parse::<u64>()
+                    .map_err(|_| SpecParseError::InvalidSeed(v.to_string()))
+            })
+            .transpose()?;
+        let recall = query_flag(&query, "recall");
         match (domain, commit) {
             (Some(domain), Some(commit)) => Ok(ResourceSpec::StartIndex {
                 domain,
                 commit,
                 previous,
+                model,
+                allow_model_mismatch,
+                seed,
+                recall,
             }),
             _ => Err(SpecParseError::NoCommitIdOrDomain),
         }
@@ -184,9 +335,11 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
         }
     } else if RE_CHECK.is_match(path) {
         let query = query_map(uri);
+        let wait = query_flag(&query, "wait");
         if let Some(task_id) = query.get("task_id") {
             Ok(ResourceSpec::CheckTask {
                 task_id: task_id.to_string(),
+                wait,
             })
         } else {
             Err(SpecParseError::NoTaskId)
@@ -196,6 +349,8 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
         let domain = query.get("domain").map(|v| v.to_string());
         let commit = query.get("commit").map(|v| v.to_string());
         let count = query.get("count").map(|v| v.parse::<usize>().unwrap());
+        let model = query_model(&query)?;
+        let allow_model_mismatch = query_flag(&query, "allow_model_mismatch");
         match (domain, commit) {
             (Some(domain), Some(commit)) => {
                 let count = count.unwrap_or(10);
@@ -203,6 +358,14 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
                     domain,
                     commit,
                     count,
+                    model,
+                    allow_model_mismatch,
+                    dedup: query_flag(&query, "dedup"),
+                    explain: query_flag(&query, "explain"),
+                    max_distance: query_max_distance(&query)?,
+                    preview: query_flag(&query, "preview"),
+                    provenance: query_flag(&query, "provenance"),
+                    filter: query_id_filter(&query)?,
                 })
             }
             _ => Err(SpecParseError::NoCommitIdOrDomain),
@@ -221,6 +384,8 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
                     commit,
                     id,
                     count,
+                    dedup: query_flag(&query, "dedup"),
+                    max_distance: query_max_distance(&query)?,
                 })
             }
             _ => Err(SpecParseError::NoCommitIdOrDomain),
@@ -237,28 +402,242 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
                     domain,
                     commit,
                     threshold,
+                    report: query_flag(&query, "report"),
+                })
+            }
+            _ => Err(SpecParseError::NoCommitIdOrDomain),
+        }
+    } else if RE_RECOMMEND.is_match(path) {
+        let query = query_map(uri);
+        let domain = query.get("domain").map(|v| v.to_string());
+        let commit = query.get("commit").map(|v| v.to_string());
+        let count = query.get("count").map(|v| v.parse::<usize>().unwrap());
+        match (domain, commit) {
+            (Some(domain), Some(commit)) => {
+                let count = count.unwrap_or(10);
+                Ok(ResourceSpec::Recommend {
+                    domain,
+                    commit,
+                    count,
+                    max_distance: query_max_distance(&query)?,
                 })
             }
             _ => Err(SpecParseError::NoCommitIdOrDomain),
         }
+    } else if RE_EMBED.is_match(path) {
+        let query = query_map(uri);
+        let domain = query.get("domain").map(|v| v.to_string());
+        let model = query_model(&query)?;
+        let allow_model_mismatch = query_flag(&query, "allow_model_mismatch");
+        Ok(ResourceSpec::Embed {
+            domain,
+            model,
+            allow_model_mismatch,
+        })
     } else if RE_STATISTICS.is_match(path) {
         Ok(ResourceSpec::GetStatistics)
+    } else if RE_MODELS.is_match(path) {
+        Ok(ResourceSpec::GetModels)
+    } else if RE_INDEXES.is_match(path) {
+        let query = query_map(uri);
+        Ok(ResourceSpec::ListIndexes {
+            domain: query.get("domain").map(|v| v.to_string()),
+        })
+    } else if RE_FILES.is_match(path) {
+        let query = query_map(uri);
+        Ok(ResourceSpec::ListFiles {
+            domain: query.get("domain").map(|v| v.to_string()),
+        })
+    } else if let Some(captures) = RE_FILE.captures(path) {
+        Ok(ResourceSpec::File {
+            name: captures[1].to_string(),
+        })
     } else {
         Err(SpecParseError::UnknownPath)
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum TaskStatus {
     Pending(f32),
     Error(String),
-    Completed(usize),
+    Completed(IndexingSummary),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct IndexingSummary {
+    indexed_documents: usize,
+    operations: usize,
+    rejected_embeddings: usize,
+    embedding_seconds: f64,
+    graph_build_seconds: f64,
+    serialization_seconds: f64,
+    serialized_size: u64,
+    // Estimated recall@RECALL_K of searches in the finished index, if
+    // it was asked for.
+    recall_estimate: Option<f32>,
+    prompt_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct QueryExplanation {
+    #[serde(flatten)]
+    search: SearchStatistics,
+    embedding_seconds: f64,
+    search_seconds: f64,
+}
+
+#[derive(Serialize)]
+struct ServerStatistics {
+    #[serde(flatten)]
+    vector_store: VectorStoreStatistics,
+    embedding_cache: EmbeddingCacheStatistics,
+    embedding_provider: ProviderHealth,
+    query_cache: QueryCacheStatistics,
+    load: LoadStatistics,
+    domains: Vec<DomainMetrics>,
+}
+
+#[derive(Serialize)]
+struct LoadStatistics {
+    in_flight: usize,
+    max_in_flight: Option<usize>,
+    /// The fraction of `max_in_flight` in use, if there is a maximum.
+    load: Option<f64>,
+    shed_requests: usize,
+    interactive_searches: usize,
+    background_scans: usize,
+}
+
+/// Seconds clients are asked to wait before retrying a shed request.
+const SHED_RETRY_AFTER_SECONDS: u64 = 1;
+
+/// Number of points a background scan processes between checks for
+/// interactive searches.
+const BACKGROUND_SCAN_BLOCK: usize = 256;
+/// How long a background scan pauses while interactive searches are
+/// in flight.
+const BACKGROUND_SCAN_BACKOFF: Duration = Duration::from_millis(10);
+
+struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl<'a> InFlightGuard<'a> {
+    fn new(counter: &'a AtomicUsize) -> (Self, usize) {
+        let in_flight = counter.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+        (InFlightGuard(counter), in_flight)
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, atomic::Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct QueryCacheKey {
+    index_id: String,
+    generation: usize,
+    query_hash: u64,
+    model: Model,
+    count: usize,
+    dedup: bool,
+    max_distance: Option<u32>,
+    provenance: bool,
+    filter: Option<IdFilter>,
+}
+
+/// A cache of search response bodies, so that identical queries
+/// repeated within `ttl` are answered without embedding or searching.
+struct QueryCache {
+    cache: Option<std::sync::Mutex<LruCache<QueryCacheKey, (Instant, String)>>>,
+    ttl: Duration,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct QueryCacheStatistics {
+    entries: usize,
+    hits: usize,
+    misses: usize,
+}
+
+impl QueryCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            cache: NonZeroUsize::new(capacity).map(|c| std::sync::Mutex::new(LruCache::new(c))),
+            ttl,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    fn get(&self, key: &QueryCacheKey) -> Option<String> {
+        let mut cache = self.cache.as_ref()?.lock().unwrap();
+        let result = match cache.get(key) {
+            Some((inserted, body)) if inserted.elapsed() < self.ttl => Some(body.clone()),
+            Some(_) => {
+                cache.pop(key);
+                None
+            }
+            None => None,
+        };
+        if result.is_some() {
+            self.hits.fetch_add(1, atomic::Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn put(&self, key: QueryCacheKey, body: String) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().put(key, (Instant::now(), body));
+        }
+    }
+
+    fn statistics(&self) -> QueryCacheStatistics {
+        QueryCacheStatistics {
+            entries: self
+                .cache
+                .as_ref()
+                .map(|c| c.lock().unwrap().len())
+                .unwrap_or(0),
+            hits: self.hits.load(atomic::Ordering::Relaxed),
+            misses: self.misses.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+// Clusters described in detail in a duplicate report.
+pub const DUPLICATE_REPORT_CLUSTERS: usize = 20;
+
+const TASK_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The recall of a finished index is estimated, when asked for, by
+// comparing searches for this many of its points with an exhaustive
+// search, which takes RECALL_SAMPLES passes over the index.
+const RECALL_SAMPLES: usize = 100;
+const RECALL_K: usize = 10;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct QueryTerm {
+    id: String,
+    #[serde(default = "default_query_term_weight")]
+    weight: f32,
+}
+
+fn default_query_term_weight() -> f32 {
+    1.0
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub struct QueryResult {
     id: String,
     distance: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provenance: Option<Provenance>,
 }
 
 pub struct Service {
@@ -266,9 +645,52 @@ pub struct Service {
     user_forward_header: String,
     path: PathBuf,
     vector_store: VectorStore,
+    embedding_cache: EmbeddingCache,
+    query_cache: QueryCache,
+    // Bumped whenever an index is replaced, invalidating cached queries.
+    index_generation: AtomicUsize,
+    invalid_embedding_policy: InvalidEmbeddingPolicy,
+    slow_query_threshold: Option<Duration>,
+    file_access_key: Option<String>,
+    preview_interval: Option<NonZeroUsize>,
+    max_in_flight: Option<usize>,
+    in_flight: AtomicUsize,
+    shed_requests: AtomicUsize,
+    // Searches, similarity and recommendation requests being answered,
+    // which background scans make way for.
+    interactive_searches: AtomicUsize,
+    max_background_scans: NonZeroUsize,
+    background_scans: Arc<Semaphore>,
     pending: Mutex<HashSet<String>>,
     tasks: RwLock<HashMap<String, TaskStatus>>,
-    indexes: RwLock<HashMap<String, Arc<HnswIndex>>>,
+    task_updates: Notify,
+    indexes: RwLock<HashMap<String, Arc<IndexState>>>,
+    // Partial snapshots of indexes that are still being built.
+    previews: RwLock<HashMap<String, Arc<IndexState>>>,
+}
+
+/// An index along with the tombstones of its deleted points.
+#[derive(Clone)]
+struct IndexState {
+    hnsw: HnswIndex,
+    tombstones: Tombstones,
+    // Built on first use. A new generation of the index gets a new
+    // state, and with it a new map.
+    ids: OnceLock<IdIndex>,
+}
+
+impl IndexState {
+    fn new(hnsw: HnswIndex, tombstones: Tombstones) -> Self {
+        IndexState {
+            hnsw,
+            tombstones,
+            ids: OnceLock::new(),
+        }
+    }
+
+    fn ids(&self) -> &IdIndex {
+        self.ids.get_or_init(|| IdIndex::new(&self.hnsw))
+    }
 }
 
 #[derive(Debug, Error)]
@@ -283,6 +705,32 @@ async fn extract_body(req: Request<Body>) -> Bytes {
 
 enum TerminusIndexOperationError {}
 
+/// A `traceparent` header value continuing the current trace, for
+/// requests made to other services.
+fn outgoing_traceparent() -> String {
+    format!(
+        "00-{}-{:016x}-01",
+        current_trace_id(),
+        rand::thread_rng().gen::<u64>()
+    )
+}
+
+/// The trace id of a W3C `traceparent` header, if it has one.
+fn trace_id_from_headers(headers: &HeaderMap) -> Option<String> {
+    let traceparent = headers.get("traceparent")?.to_str().ok()?;
+    let mut parts = traceparent.split('-');
+    let _version = parts.next()?;
+    let trace_id = parts.next()?;
+    if trace_id.len() == 32
+        && trace_id.bytes().all(|b| b.is_ascii_hexdigit())
+        && trace_id.bytes().any(|b| b != b'0')
+    {
+        Some(trace_id.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
 async fn get_operations_from_content_endpoint(
     content_endpoint: String,
     user_forward_header: String,
@@ -300,6 +748,7 @@ async fn get_operations_from_content_endpoint(
     let res = client
         .get(url)
         .header(user_forward_header, "admin")
+        .header("traceparent", outgoing_traceparent())
         .send()
         .await
         .unwrap();
@@ -348,6 +797,82 @@ enum ResponseError {
     IdMissing(String),
     #[error("Embedding error: {0:?}")]
     EmbeddingError(#[from] EmbeddingError),
+    #[error("{0}")]
+    ModelMismatch(#[from] ModelMismatchError),
+    #[error("The weighted query terms cancel out")]
+    EmptyQuery,
+}
+
+#[derive(Debug, Serialize)]
+struct FileEntry {
+    name: String,
+    size: u64,
+    modified: Option<String>,
+}
+
+#[derive(Debug, Error)]
+enum FileError {
+    #[error("File access is not allowed")]
+    Forbidden,
+    #[error("File not found")]
+    NotFound,
+    #[error("Range not satisfiable for file of length {0}")]
+    RangeNotSatisfiable(u64),
+    #[error("{0:?}")]
+    IoError(#[from] std::io::Error),
+}
+
+impl FileError {
+    fn status(&self) -> StatusCode {
+        match self {
+            FileError::Forbidden => StatusCode::FORBIDDEN,
+            FileError::NotFound => StatusCode::NOT_FOUND,
+            FileError::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            FileError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Parse a single `bytes=` range, returning the inclusive start and
+/// end offsets if the range can be served from a file of length `len`.
+fn parse_byte_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let last = len.checked_sub(1)?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (len - suffix.min(len), last)
+        }
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(last)),
+    };
+    if start > end {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
+/// The domain a file in the storage directory belongs to: the part
+/// before the `@` of an index file, or before the extension of a
+/// domain file.
+fn file_domain(name: &str) -> Option<String> {
+    let name = name.strip_suffix(".tmp").unwrap_or(name);
+    let encoded = match name.split_once('@') {
+        Some((domain, _)) => domain,
+        None => name.rsplit_once('.')?.0,
+    };
+    decode(encoded).ok().map(|domain| domain.into_owned())
+}
+
+/// Compare two secrets in a time that depends only on their lengths,
+/// so that response times do not reveal how much of a guess matched.
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    let difference = left
+        .iter()
+        .zip(right)
+        .fold(0, |difference, (l, r)| difference | (l ^ r));
+    std::hint::black_box(difference) == 0 && left.len() == right.len()
 }
 
 fn add_to_duplicates(duplicates: &mut HashMap<usize, usize>, id1: usize, id2: usize) {
@@ -363,19 +888,58 @@ impl Service {
 
     async fn set_task_status(&self, task_id: String, status: TaskStatus) {
         self.tasks.write().await.insert(task_id, status);
+        self.task_updates.notify_waiters();
+    }
+
+    /// Wait until a pending task changes status, or until
+    /// `TASK_WAIT_TIMEOUT` passes, and return the status at that point.
+    /// A task that is not known yet is waited for like a pending one.
+    async fn wait_for_task_status(&self, task_id: &str) -> Option<TaskStatus> {
+        let deadline = tokio::time::Instant::now() + TASK_WAIT_TIMEOUT;
+        let initial = self.get_task_status(task_id).await;
+        if !matches!(initial, None | Some(TaskStatus::Pending(_))) {
+            return initial;
+        }
+        loop {
+            // Register interest before rechecking so that we can't
+            // miss an update that happens in between.
+            let notified = self.task_updates.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let current = self.get_task_status(task_id).await;
+            if current != initial {
+                return current;
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return current;
+            }
+        }
     }
 
-    async fn get_index(&self, index_id: &str) -> io::Result<Arc<HnswIndex>> {
-        if let Some(hnsw) = self.indexes.read().await.get(index_id) {
-            Ok(hnsw).cloned()
+    async fn get_index(&self, index_id: &str) -> io::Result<Arc<IndexState>> {
+        if let Some(index) = self.indexes.read().await.get(index_id) {
+            Ok(index).cloned()
         } else {
             let mut path = self.path.clone();
-            Ok(deserialize_index(&mut path, index_id, &self.vector_store)?.into())
+            let (hnsw, tombstones) =
+                deserialize_index_with_tombstones(&mut path, index_id, &self.vector_store)?;
+            // Keep the index, so that it and its id map are only built
+            // once. An index set in the meantime is newer.
+            let index = Arc::new(IndexState::new(hnsw, tombstones));
+            Ok(self
+                .indexes
+                .write()
+                .await
+                .entry(index_id.to_string())
+                .or_insert(index)
+                .clone())
         }
     }
 
-    async fn set_index(&self, index_id: String, hnsw: Arc<HnswIndex>) {
-        self.indexes.write().await.insert(index_id, hnsw);
+    async fn set_index(&self, index_id: String, index: Arc<IndexState>) {
+        self.indexes.write().await.insert(index_id, index);
+        self.index_generation
+            .fetch_add(1, atomic::Ordering::Relaxed);
     }
 
     async fn test_and_set_pending(&self, index_id: String) -> bool {
@@ -390,6 +954,37 @@ impl Service {
 
     async fn clear_pending(&self, index_id: &str) {
         self.pending.lock().await.remove(index_id);
+        self.previews.write().await.remove(index_id);
+    }
+
+    /// Get the index, or if it is still being built and `preview` is
+    /// set, its latest partial snapshot. The flag returned is true for
+    /// a partial snapshot.
+    async fn get_index_or_preview(
+        &self,
+        index_id: &str,
+        preview: bool,
+    ) -> io::Result<(Arc<IndexState>, bool)> {
+        match self.get_index(index_id).await {
+            Ok(index) => Ok((index, false)),
+            Err(e) if preview => match self.previews.read().await.get(index_id) {
+                Some(index) => Ok((index.clone(), true)),
+                None => Err(e),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The api key for the embedding provider passed with a request.
+    /// A provider that needs no key gets an empty one when none is
+    /// given.
+    fn embedding_api_key(&self, headers: &HeaderMap) -> Result<String, HeaderError> {
+        match get_header_value(headers, "VECTORLINK_EMBEDDING_API_KEY") {
+            Err(HeaderError::MissingKey(_)) if !self.embedding_cache.provider().needs_key() => {
+                Ok(String::new())
+            }
+            result => result,
+        }
     }
 
     fn generate_task() -> String {
@@ -401,11 +996,20 @@ impl Service {
         s
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn new<P: Into<PathBuf>>(
         path: P,
         user_forward_header: String,
         num_bufs: usize,
         content_endpoint: Option<String>,
+        embedding_cache: EmbeddingCache,
+        invalid_embedding_policy: InvalidEmbeddingPolicy,
+        slow_query_threshold: Option<Duration>,
+        file_access_key: Option<String>,
+        query_cache: QueryCache,
+        preview_interval: Option<NonZeroUsize>,
+        max_in_flight: Option<usize>,
+        max_background_scans: NonZeroUsize,
     ) -> Self {
         let path = path.into();
         Service {
@@ -413,38 +1017,113 @@ impl Service {
             user_forward_header,
             path: path.clone(),
             vector_store: VectorStore::new(path, num_bufs),
+            embedding_cache,
+            query_cache,
+            index_generation: AtomicUsize::new(0),
+            invalid_embedding_policy,
+            slow_query_threshold,
+            file_access_key,
+            preview_interval,
+            max_in_flight,
+            in_flight: AtomicUsize::new(0),
+            shed_requests: AtomicUsize::new(0),
+            interactive_searches: AtomicUsize::new(0),
+            max_background_scans,
+            background_scans: Arc::new(Semaphore::new(max_background_scans.get())),
             pending: Mutex::new(HashSet::new()),
             tasks: RwLock::new(HashMap::new()),
+            task_updates: Notify::new(),
             indexes: RwLock::new(HashMap::new()),
+            previews: RwLock::new(HashMap::new()),
         }
     }
 
     async fn serve(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        eprintln!(
-            "{:?}: {:?} {:?}",
-            chrono::offset::Local::now(),
-            req.method(),
-            req.uri()
-        );
-        match *req.method() {
-            Method::POST => self.post(req).await,
-            Method::GET => self.get(req).await,
-            _ => todo!(),
+        let trace_id = trace_id_from_headers(req.headers())
+            .unwrap_or_else(|| format!("{:032x}", rand::thread_rng().gen::<u128>()));
+        let service = self.clone();
+        let spec = uri_to_spec(req.uri());
+        // Long polls for a task only wait, so they are not counted as
+        // in flight and are never shed.
+        let long_poll = matches!(spec, Ok(ResourceSpec::CheckTask { wait: true, .. }));
+        let (_guard, in_flight) = if long_poll {
+            (None, service.in_flight.load(atomic::Ordering::Relaxed))
+        } else {
+            let (guard, in_flight) = InFlightGuard::new(&service.in_flight);
+            (Some(guard), in_flight)
+        };
+        // Statistics are always served, so operators can see the load.
+        let shed = service.max_in_flight.is_some_and(|max| in_flight > max)
+            && !long_poll
+            && !matches!(spec, Ok(ResourceSpec::GetStatistics));
+        let response = TRACE_ID.scope(trace_id.clone(), async move {
+            log!("{:?} {:?}", req.method(), req.uri());
+            if shed {
+                log!("shedding request with {in_flight} requests in flight");
+                self.shed_requests.fetch_add(1, atomic::Ordering::Relaxed);
+                return Ok(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(header::RETRY_AFTER, SHED_RETRY_AFTER_SECONDS)
+                    .body("Server is overloaded, retry later".into())
+                    .unwrap());
+            }
+            match *req.method() {
+                Method::POST => self.post(req).await,
+                Method::GET => self.get(req).await,
+                _ => method_not_allowed(),
+            }
+        });
+        let mut response = response.await?;
+        response
+            .headers_mut()
+            .insert("X-Trace-Id", HeaderValue::from_str(&trace_id).unwrap());
+        if let Some(load) = service.load_statistics().load {
+            response.headers_mut().insert(
+                "X-Vectorlink-Load",
+                HeaderValue::from((load * 100.0).round() as u64),
+            );
+        }
+        Ok(response)
+    }
+
+    fn load_statistics(&self) -> LoadStatistics {
+        let in_flight = self.in_flight.load(atomic::Ordering::Relaxed);
+        LoadStatistics {
+            in_flight,
+            max_in_flight: self.max_in_flight,
+            load: self
+                .max_in_flight
+                .map(|max| in_flight as f64 / max.max(1) as f64),
+            shed_requests: self.shed_requests.load(atomic::Ordering::Relaxed),
+            interactive_searches: self.interactive_searches.load(atomic::Ordering::Relaxed),
+            background_scans: self.max_background_scans.get()
+                - self.background_scans.available_permits(),
+        }
+    }
+
+    /// Pause a background scan briefly while interactive searches are
+    /// in flight, so that they keep their latency.
+    fn yield_to_interactive(&self) {
+        if self.interactive_searches.load(atomic::Ordering::Relaxed) > 0 {
+            std::thread::sleep(BACKGROUND_SCAN_BACKOFF);
         }
     }
 
-    async fn load_hnsw_for_indexing(&self, idxid: IndexIdentifier) -> HnswIndex {
+    async fn load_hnsw_for_indexing(&self, idxid: IndexIdentifier) -> Arc<IndexState> {
         if let Some(previous_id) = idxid.previous {
             //let commit = idxid.commit;
             let domain = idxid.domain;
             let previous_id = create_index_name(&domain, &previous_id);
-            let hnsw = self.get_index(&previous_id).await.unwrap();
-            (*hnsw).clone()
+            self.get_index(&previous_id).await.unwrap()
         } else {
-            Hnsw::new(OpenAI)
+            Arc::new(IndexState::new(
+                new_index(idxid.seed),
+                Tombstones::default(),
+            ))
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn start_indexing_inner(
         self: Arc<Self>,
         domain: String,
@@ -452,9 +1131,13 @@ impl Service {
         previous: Option<String>,
         task_id: &str,
         api_key: String,
+        model: Option<Model>,
+        allow_model_mismatch: bool,
+        seed: Option<u64>,
+        recall: bool,
         index_id: &str,
         content_endpoint: String,
-    ) -> Result<(String, HnswIndex), IndexError> {
+    ) -> Result<(String, Arc<IndexState>, IndexingSummary), IndexError> {
         let internal_task_id = task_id;
         let opstream = get_operations_from_content_endpoint(
             content_endpoint.to_string(),
@@ -466,11 +1149,22 @@ impl Service {
         .await?
         .chunks(100);
         self.process_operation_chunks(
-            opstream, domain, commit, previous, index_id, task_id, &api_key,
+            opstream,
+            domain,
+            commit,
+            previous,
+            index_id,
+            task_id,
+            &api_key,
+            model,
+            allow_model_mismatch,
+            seed,
+            recall,
         )
         .await
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn start_indexing(
         self: Arc<Self>,
         domain: String,
@@ -478,11 +1172,21 @@ impl Service {
         previous: Option<String>,
         task_id: String,
         api_key: String,
+        model: Option<Model>,
+        allow_model_mismatch: bool,
+        seed: Option<u64>,
+        recall: bool,
     ) -> Result<(), StartIndexError> {
         let content_endpoint = self.content_endpoint.clone();
         let internal_task_id = task_id.clone();
         if let Some(content_endpoint) = content_endpoint {
-            tokio::spawn(async move {
+            log!("starting indexing task {task_id}");
+            let context = TaskContext {
+                task_id: task_id.clone(),
+                domain: domain.clone(),
+                commit: commit.clone(),
+            };
+            let task = TASK_CONTEXT.scope(context, async move {
                 let index_id = create_index_name(&domain, &commit);
                 if self.test_and_set_pending(index_id.clone()).await {
                     match self
@@ -493,24 +1197,23 @@ impl Service {
                             previous,
                             &task_id,
                             api_key,
+                            model,
+                            allow_model_mismatch,
+                            seed,
+                            recall,
                             &index_id,
                             content_endpoint,
                         )
                         .await
                     {
-                        Ok((id, hnsw)) => {
-                            let layer_len = hnsw.layer_len(0);
-                            self.set_index(id, hnsw.into()).await;
-                            self.set_task_status(task_id, TaskStatus::Completed(layer_len.clone()))
+                        Ok((id, index, summary)) => {
+                            self.set_index(id, index).await;
+                            self.set_task_status(task_id, TaskStatus::Completed(summary))
                                 .await;
                             self.clear_pending(&index_id).await;
                         }
                         Err(err) => {
-                            eprintln!(
-                                "{:?}: error while indexing: {:?}",
-                                chrono::offset::Local::now(),
-                                err
-                            );
+                            log!("error while indexing task {task_id}: {:?}", err);
                             self.set_task_status(
                                 internal_task_id,
                                 TaskStatus::Error(err.to_string()),
@@ -521,6 +1224,7 @@ impl Service {
                     }
                 }
             });
+            tokio::spawn(TRACE_ID.scope(current_trace_id(), task));
             Ok(())
         } else {
             Err(StartIndexError::NoContentEndpoint)
@@ -536,13 +1240,15 @@ impl Service {
         let source_name = create_index_name(&domain, &source_commit);
         let target_name = create_index_name(&domain, &target_commit);
         let index = self.get_index(&source_name).await?;
-        let mut indexes = self.indexes.write().await;
-        indexes.insert(target_name.clone(), index.clone());
-        std::mem::drop(indexes);
-        tokio::task::block_in_place(move || {
-            let path = self.path.clone();
-            serialize_index(path, &target_name, (*index).clone()).unwrap();
-        });
+        tokio::task::block_in_place(|| {
+            serialize_index(
+                self.path.clone(),
+                &target_name,
+                &index.hnsw,
+                &index.tombstones,
+            )
+        })?;
+        self.set_index(target_name, index).await;
         Ok(())
     }
 
@@ -558,42 +1264,150 @@ impl Service {
         index_id: &str,
         task_id: &str,
         api_key: &str,
-    ) -> Result<(String, HnswIndex), IndexError> {
+        model: Option<Model>,
+        allow_model_mismatch: bool,
+        seed: Option<u64>,
+        recall: bool,
+    ) -> Result<(String, Arc<IndexState>, IndexingSummary), IndexError> {
         let id = create_index_name(&domain, &commit);
-        let mut hnsw = self
+        // Shared with the previous index and with published previews
+        // until it changes, and only then copied.
+        let mut state = self
             .load_hnsw_for_indexing(IndexIdentifier {
                 domain: domain.clone(),
-                commit,
+                commit: commit.clone(),
                 previous,
+                seed,
             })
             .await;
+        let mut ids = match state.ids.get() {
+            Some(ids) => ids.clone(),
+            None => IdIndex::new(&state.hnsw),
+        };
         let domain = self.vector_store.get_domain(&domain)?;
+        let model = model_for_indexing(
+            &domain,
+            model,
+            self.embedding_cache.provider(),
+            allow_model_mismatch,
+        )?;
+        let mut summary = IndexingSummary::default();
+        let mut next_preview = self.preview_interval.map(NonZeroUsize::get);
+        let mut lines_read = 0;
         self.set_task_status(task_id.to_string(), TaskStatus::Pending(0.3))
             .await;
         while let Some(structs) = opstream.next().await {
-            let new_ops =
-                operations_to_point_operations(&domain, &self.vector_store, structs, api_key)
-                    .await?;
-            hnsw = start_indexing_from_operations(hnsw, new_ops)?;
+            let start = Instant::now();
+            let ops: Vec<Operation> = structs.into_iter().collect::<Result<_, _>>()?;
+            let numbered: Vec<(usize, Operation)> = (lines_read + 1..).zip(ops.clone()).collect();
+            lines_read += ops.len();
+            let (new_ops, rejected, usage) = loop {
+                let result = operations_to_point_operations(
+                    &domain,
+                    &self.vector_store,
+                    ops.iter().cloned().map(Ok).collect(),
+                    api_key,
+                    model,
+                    &self.embedding_cache,
+                    self.invalid_embedding_policy,
+                )
+                .await;
+                match result {
+                    // Pause rather than fail while the provider is down.
+                    Err(IndexError::EmbeddingError(EmbeddingError::CircuitOpen)) => {
+                        let pause = self.embedding_cache.breaker().reset_timeout();
+                        log!(
+                            "embedding provider unavailable, pausing task {task_id} for {pause:?}"
+                        );
+                        tokio::time::sleep(pause).await;
+                    }
+                    result => break result?,
+                }
+            };
+            for RejectedEmbedding { id, problem } in rejected.iter() {
+                log!("skipping {id}: {problem}");
+            }
+            domain.record_provenance(provenance_for(&commit, &numbered, &new_ops))?;
+            summary.embedding_seconds += start.elapsed().as_secs_f64();
+            summary.operations += new_ops.len();
+            summary.rejected_embeddings += rejected.len();
+            summary.prompt_tokens += usage.prompt_tokens;
+            let start = Instant::now();
+            let index = Arc::make_mut(&mut state);
+            // Any id map was built for the index as it was before.
+            index.ids.take();
+            apply_operations(&mut index.hnsw, &mut index.tombstones, &mut ids, new_ops);
+            summary.graph_build_seconds += start.elapsed().as_secs_f64();
+            if let (Some(interval), Some(threshold)) = (self.preview_interval, next_preview) {
+                let len = state.hnsw.layer_len(0);
+                if len >= threshold {
+                    log!("publishing preview of {id} with {len} vectors");
+                    self.previews
+                        .write()
+                        .await
+                        .insert(id.clone(), state.clone());
+                    let interval = interval.get();
+                    next_preview = Some((len / interval + 1) * interval);
+                }
+            }
         }
         self.set_task_status(task_id.to_string(), TaskStatus::Pending(0.8))
             .await;
         let path = self.path.clone();
-        serialize_index(path, index_id, hnsw.clone())?;
-        Ok((id, hnsw))
+        let start = Instant::now();
+        summary.serialized_size = serialize_index(path, index_id, &state.hnsw, &state.tombstones)?;
+        summary.serialization_seconds = start.elapsed().as_secs_f64();
+        summary.indexed_documents = state.hnsw.layer_len(0);
+        if recall {
+            summary.recall_estimate = task::block_in_place(|| {
+                estimate_recall(
+                    &state.hnsw,
+                    RECALL_K,
+                    RECALL_SAMPLES,
+                    &mut rand::thread_rng(),
+                )
+            });
+        }
+        // A state still shared with a preview builds its id map on
+        // first use instead.
+        if let Some(index) = Arc::get_mut(&mut state) {
+            index.ids = ids.into();
+        }
+        Ok((id, state, summary))
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn get_start_index(
         self: Arc<Self>,
         req: Request<Body>,
         domain: String,
         commit: String,
         previous: Option<String>,
+        model: Option<Model>,
+        allow_model_mismatch: bool,
+        seed: Option<u64>,
+        recall: bool,
     ) -> Result<String, ResponseError> {
         let task_id = Service::generate_task();
-        let api_key = get_header_value(req.headers(), "VECTORLINK_EMBEDDING_API_KEY")?;
-        self.set_task_status(task_id.clone(), TaskStatus::Pending(0.0));
-        self.start_indexing(domain, commit, previous, task_id.clone(), api_key)?;
+        let api_key = self.embedding_api_key(req.headers())?;
+        // The task is known from the moment its id is handed out, so
+        // that checking on it right away does not find nothing.
+        self.set_task_status(task_id.clone(), TaskStatus::Pending(0.0))
+            .await;
+        if let Err(e) = self.start_indexing(
+            domain,
+            commit,
+            previous,
+            task_id.clone(),
+            api_key,
+            model,
+            allow_model_mismatch,
+            seed,
+            recall,
+        ) {
+            self.tasks.write().await.remove(&task_id);
+            return Err(e.into());
+        }
         Ok(task_id)
     }
 
@@ -604,8 +1418,23 @@ impl Service {
                 domain,
                 commit,
                 previous,
+                model,
+                allow_model_mismatch,
+                seed,
+                recall,
             }) => {
-                let result = self.get_start_index(req, domain, commit, previous).await;
+                let result = self
+                    .get_start_index(
+                        req,
+                        domain,
+                        commit,
+                        previous,
+                        model,
+                        allow_model_mismatch,
+                        seed,
+                        recall,
+                    )
+                    .await;
                 string_response_or_error(result)
             }
             Ok(ResourceSpec::AssignIndex {
@@ -624,8 +1453,13 @@ impl Service {
                         .unwrap()),
                 }
             }
-            Ok(ResourceSpec::CheckTask { task_id }) => {
-                if let Some(state) = self.get_task_status(&task_id).await {
+            Ok(ResourceSpec::CheckTask { task_id, wait }) => {
+                let state = if wait {
+                    self.wait_for_task_status(&task_id).await
+                } else {
+                    self.get_task_status(&task_id).await
+                };
+                if let Some(state) = state {
                     match state {
                         TaskStatus::Pending(f) => {
                             let obj = json!({"status":"Pending","percentage":f});
@@ -635,8 +1469,12 @@ impl Service {
                             .status(StatusCode::INTERNAL_SERVER_ERROR)
                             .body(format!("{:?}", msg).into())
                             .unwrap()),
-                        TaskStatus::Completed(u) => {
-                            let obj = json!({"status":"Complete","indexed_documents":u});
+                        TaskStatus::Completed(summary) => {
+                            let obj = json!({
+                                "status":"Complete",
+                                "indexed_documents":summary.indexed_documents,
+                                "summary":summary
+                            });
                             Ok(Response::builder().body(obj.to_string().into()).unwrap())
                         }
                     }
@@ -648,9 +1486,10 @@ impl Service {
                 domain,
                 commit,
                 threshold,
+                report,
             }) => {
                 let result = self
-                    .get_duplicate_candidates(domain, commit, threshold)
+                    .get_duplicate_candidates(domain, commit, threshold, report)
                     .await;
                 string_response_or_error(result)
             }
@@ -659,16 +1498,59 @@ impl Service {
                 commit,
                 count,
                 id,
+                dedup,
+                max_distance,
             }) => {
-                let result = self.get_similar_documents(domain, commit, id, count).await;
+                let result = self
+                    .get_similar_documents(domain, commit, id, count, dedup, max_distance)
+                    .await;
                 string_response_or_error(result)
             }
+            Ok(ResourceSpec::ListIndexes { domain }) => {
+                let path = self.path.clone();
+                let result = tokio::task::block_in_place(|| -> Result<String, ResponseError> {
+                    let metadata = list_index_metadata(path, domain.as_deref())?;
+                    Ok(serde_json::to_string(&metadata)?)
+                });
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::ListFiles { domain }) => {
+                match self.list_files(req.headers(), domain.as_deref()).await {
+                    Ok(files) => {
+                        json_response_or_error(serde_json::to_string(&files).map_err(|e| e.into()))
+                    }
+                    Err(e) => Ok(Response::builder()
+                        .status(e.status())
+                        .body(e.to_string().into())
+                        .unwrap()),
+                }
+            }
+            Ok(ResourceSpec::File { name }) => {
+                match self.file_response(req.headers(), &name).await {
+                    Ok(response) => Ok(response),
+                    Err(e) => Ok(Response::builder()
+                        .status(e.status())
+                        .body(e.to_string().into())
+                        .unwrap()),
+                }
+            }
             Ok(ResourceSpec::GetStatistics) => {
-                let statistics = self.vector_store.statistics();
+                let statistics = ServerStatistics {
+                    vector_store: self.vector_store.statistics(),
+                    embedding_cache: self.embedding_cache.statistics(),
+                    embedding_provider: self.embedding_cache.breaker().health(),
+                    query_cache: self.query_cache.statistics(),
+                    load: self.load_statistics(),
+                    domains: self.vector_store.domain_metrics(),
+                };
                 let json_string = serde_json::to_string_pretty(&statistics).map_err(|e| e.into());
                 json_response_or_error(json_string)
             }
-            Ok(_) => todo!(),
+            Ok(ResourceSpec::GetModels) => {
+                let models: Vec<_> = Model::all().iter().map(Model::info).collect();
+                json_response_or_error(serde_json::to_string(&models).map_err(|e| e.into()))
+            }
+            Ok(_) => method_not_allowed(),
             Err(e) => Ok(Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(e.to_string().into())
@@ -682,25 +1564,27 @@ impl Service {
         commit: String,
         id: String,
         count: usize,
+        dedup: bool,
+        max_distance: Option<f32>,
     ) -> Result<String, ResponseError> {
+        let (_interactive, _) = InFlightGuard::new(&self.interactive_searches);
         let index_id = create_index_name(&domain, &commit);
         // if None, then return 404
-        let hnsw = self.get_index(&index_id).await?;
-        let elts = hnsw.layer_len(0);
-        let mut qp = None;
-        for i in 0..elts {
-            if *hnsw.feature(i).id() == id {
-                qp = Some(hnsw.feature(i))
-            }
-        }
-        match qp {
+        let index = self.get_index(&index_id).await?;
+        let hnsw = &index.hnsw;
+        match find_point(hnsw, &index.tombstones, &id) {
             Some(qp) => {
-                let res = search(qp, count, &hnsw)?;
+                let res = search_live(qp, count, hnsw, &index.tombstones)?;
+                let mut res = within_distance(res, max_distance);
+                if dedup {
+                    res = dedup_by_id(res);
+                }
                 let ids: Vec<QueryResult> = res
                     .iter()
                     .map(|p| QueryResult {
                         id: p.id().to_string(),
                         distance: f32::from_bits(p.distance()),
+                        provenance: None,
                     })
                     .collect();
                 let s = serde_json::to_string(&ids)?;
@@ -715,27 +1599,51 @@ impl Service {
         domain: String,
         commit: String,
         threshold: f32,
+        report: bool,
     ) -> Result<String, ResponseError> {
         let index_id = create_index_name(&domain, &commit);
         // if None, then return 404
-        let hnsw = self.get_index(&index_id).await?;
-        let mut duplicates: HashMap<usize, usize> = HashMap::new();
-        let elts = hnsw.layer_len(0);
-        for i in 0..elts {
-            let current_point = &hnsw.feature(i);
-            let results = search(current_point, 2, &hnsw)?;
-            for result in results.iter() {
-                if f32::from_bits(result.distance()) < threshold {
-                    add_to_duplicates(&mut duplicates, i, result.internal_id())
+        let index = self.get_index(&index_id).await?;
+        // Scans run off the async runtime, a limited number at a time,
+        // and back off while interactive searches are being answered.
+        // The permit moves into the scan, so that it is held until the
+        // scan is done even if the request is dropped before then.
+        let permit = self.background_scans.clone().acquire_owned().await.unwrap();
+        let service = self.clone();
+        task::spawn_blocking(move || -> Result<String, ResponseError> {
+            let _permit = permit;
+            let hnsw = &index.hnsw;
+            let mut duplicates: HashMap<usize, usize> = HashMap::new();
+            let elts = hnsw.layer_len(0);
+            for i in 0..elts {
+                if i % BACKGROUND_SCAN_BLOCK == 0 {
+                    service.yield_to_interactive();
+                }
+                let current_point = &hnsw.feature(i);
+                if index.tombstones.is_deleted(i) {
+                    continue;
+                }
+                let results = search_live(current_point, 2, hnsw, &index.tombstones)?;
+                for result in results.iter() {
+                    if f32::from_bits(result.distance()) < threshold {
+                        add_to_duplicates(&mut duplicates, i, result.internal_id())
+                    }
                 }
             }
-        }
-        let mut v: Vec<(&str, &str)> = duplicates
-            .into_iter()
-            .map(|(i, j)| (hnsw.feature(i).id(), hnsw.feature(j).id()))
-            .collect();
-        let result = serde_json::to_string(&v)?;
-        Ok(result)
+            if report {
+                let pairs: Vec<(usize, usize)> = duplicates.into_iter().collect();
+                let report = duplicate_report(hnsw, &pairs, DUPLICATE_REPORT_CLUSTERS);
+                return Ok(serde_json::to_string(&report)?);
+            }
+            let mut v: Vec<(&str, &str)> = duplicates
+                .into_iter()
+                .map(|(i, j)| (hnsw.feature(i).id(), hnsw.feature(j).id()))
+                .collect();
+            let result = serde_json::to_string(&v)?;
+            Ok(result)
+        })
+        .await
+        .unwrap()
     }
 
     async fn post(&self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
@@ -745,14 +1653,37 @@ impl Service {
                 domain,
                 commit,
                 count,
+                model,
+                allow_model_mismatch,
+                dedup,
+                explain,
+                max_distance,
+                preview,
+                provenance,
+                filter,
             }) => {
                 let headers = req.headers().clone();
                 let body = req.into_body();
                 let body_bytes = hyper::body::to_bytes(body).await.unwrap();
                 let q = String::from_utf8(body_bytes.to_vec()).unwrap();
-                let api_key = get_header_value(&headers, "VECTORLINK_EMBEDDING_API_KEY");
-                let result: Result<Response<Body>, ResponseError> =
-                    self.index_response(api_key, q, domain, commit, count).await;
+                let api_key = self.embedding_api_key(&headers);
+                let result: Result<Response<Body>, ResponseError> = self
+                    .index_response(
+                        api_key,
+                        q,
+                        domain,
+                        commit,
+                        count,
+                        model,
+                        allow_model_mismatch,
+                        dedup,
+                        explain,
+                        max_distance,
+                        preview,
+                        provenance,
+                        filter,
+                    )
+                    .await;
                 match result {
                     Ok(body) => Ok(body),
                     Err(e) => Ok(Response::builder()
@@ -761,7 +1692,38 @@ impl Service {
                         .unwrap()),
                 }
             }
-            Ok(_) => todo!(),
+            Ok(ResourceSpec::Recommend {
+                domain,
+                commit,
+                count,
+                max_distance,
+            }) => {
+                let body_bytes = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let result = match serde_json::from_slice(&body_bytes) {
+                    Ok(terms) => {
+                        self.recommend_response(terms, domain, commit, count, max_distance)
+                            .await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::Embed {
+                domain,
+                model,
+                allow_model_mismatch,
+            }) => {
+                let headers = req.headers().clone();
+                let body = req.into_body();
+                let body_bytes = hyper::body::to_bytes(body).await.unwrap();
+                let q = String::from_utf8(body_bytes.to_vec()).unwrap();
+                let api_key = self.embedding_api_key(&headers);
+                let result = self
+                    .embed_response(api_key, q, domain, model, allow_model_mismatch)
+                    .await;
+                json_response_or_error(result)
+            }
+            Ok(_) => method_not_allowed(),
             Err(e) => Ok(Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(e.to_string().into())
@@ -769,6 +1731,174 @@ impl Service {
         }
     }
 
+    /// Search with the normalized weighted sum of the vectors of the
+    /// given documents, excluding those documents from the result.
+    async fn recommend_response(
+        &self,
+        terms: Vec<QueryTerm>,
+        domain: String,
+        commit: String,
+        count: usize,
+        max_distance: Option<f32>,
+    ) -> Result<String, ResponseError> {
+        let (_interactive, _) = InFlightGuard::new(&self.interactive_searches);
+        let index_id = create_index_name(&domain, &commit);
+        let index = self.get_index(&index_id).await?;
+        let hnsw = &index.hnsw;
+        let mut weighted = Vec::with_capacity(terms.len());
+        for term in terms.iter() {
+            match find_point(hnsw, &index.tombstones, &term.id) {
+                Some(point) => weighted.push((point.vec(), term.weight)),
+                None => return Err(ResponseError::IdMissing(term.id.clone())),
+            }
+        }
+        let vec = normalized_weighted_sum(weighted).ok_or(ResponseError::EmptyQuery)?;
+        let qp = Point::Mem { vec: Box::new(vec) };
+        let wanted = count + terms.len();
+        let res = search_live(&qp, wanted, hnsw, &index.tombstones)?;
+        let res = within_distance(res, max_distance);
+        let ids: Vec<QueryResult> = res
+            .iter()
+            .filter(|p| terms.iter().all(|t| t.id != p.id()))
+            .take(count)
+            .map(|p| QueryResult {
+                id: p.id().to_string(),
+                distance: f32::from_bits(p.distance()),
+                provenance: None,
+            })
+            .collect();
+        let s = serde_json::to_string(&ids)?;
+        Ok(s)
+    }
+
+    async fn embed_response(
+        &self,
+        api_key: Result<String, HeaderError>,
+        q: String,
+        domain: Option<String>,
+        model: Option<Model>,
+        allow_model_mismatch: bool,
+    ) -> Result<String, ResponseError> {
+        let api_key = api_key?;
+        let domain = domain
+            .map(|domain| self.vector_store.get_domain(&domain))
+            .transpose()?;
+        let model = match &domain {
+            Some(domain) => domain.resolve_model(
+                model,
+                self.embedding_cache.provider(),
+                allow_model_mismatch,
+            )?,
+            None => model.unwrap_or_default(),
+        };
+        let embeddings = self
+            .embedding_cache
+            .embeddings_for(&api_key, &[model.query_text(&q)], model)
+            .await?;
+        if let Some(domain) = domain {
+            domain.record_embedding_tokens(embeddings.usage.prompt_tokens);
+        }
+        let obj = json!({
+            "model": model,
+            "model_version": embeddings.model,
+            "usage": embeddings.usage,
+            "embedding": &embeddings.embeddings[0][..],
+        });
+        Ok(obj.to_string())
+    }
+
+    /// Serve a vector or index file from the storage directory,
+    /// honoring a single byte range if one is requested.
+    async fn file_response(
+        &self,
+        headers: &HeaderMap,
+        name: &str,
+    ) -> Result<Response<Body>, FileError> {
+        self.check_file_access(headers)?;
+        let mut path = self.path.clone();
+        path.push(name);
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(FileError::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.metadata().await?.len();
+        let builder = Response::builder().header(header::ACCEPT_RANGES, "bytes");
+        let (builder, start, count) = match headers.get(header::RANGE) {
+            Some(range) => {
+                let (start, end) = range
+                    .to_str()
+                    .ok()
+                    .and_then(|range| parse_byte_range(range, len))
+                    .ok_or(FileError::RangeNotSatisfiable(len))?;
+                let builder = builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"));
+                (builder, start, end - start + 1)
+            }
+            None => (builder, 0, len),
+        };
+        file.seek(SeekFrom::Start(start)).await?;
+        let body = Body::wrap_stream(ReaderStream::new(file.take(count)));
+        Ok(builder
+            .header(header::CONTENT_LENGTH, count)
+            .body(body)
+            .unwrap())
+    }
+
+    fn check_file_access(&self, headers: &HeaderMap) -> Result<(), FileError> {
+        match (
+            &self.file_access_key,
+            headers.get("VECTORLINK_FILE_ACCESS_KEY"),
+        ) {
+            (Some(key), Some(given)) if constant_time_eq(given.as_bytes(), key.as_bytes()) => {
+                Ok(())
+            }
+            _ => Err(FileError::Forbidden),
+        }
+    }
+
+    /// List the files in the storage directory and the download
+    /// staging area, optionally only those belonging to `domain`.
+    async fn list_files(
+        &self,
+        headers: &HeaderMap,
+        domain: Option<&str>,
+    ) -> Result<Vec<FileEntry>, FileError> {
+        self.check_file_access(headers)?;
+        let belongs = |name: &str| match domain {
+            Some(domain) => file_domain(name).as_deref() == Some(domain),
+            None => true,
+        };
+        let mut files = Vec::new();
+        let mut directories = vec![(self.path.clone(), String::new())];
+        while let Some((directory, relative)) = directories.pop() {
+            let mut entries = tokio::fs::read_dir(&directory).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let metadata = entry.metadata().await?;
+                let name = format!("{relative}{name}");
+                if metadata.is_dir() {
+                    directories.push((entry.path(), format!("{name}/")));
+                    continue;
+                }
+                if !belongs(name.rsplit('/').next().unwrap()) {
+                    continue;
+                }
+                files.push(FileEntry {
+                    name,
+                    size: metadata.len(),
+                    modified: metadata.modified().ok().map(|modified| {
+                        chrono::DateTime::<chrono::Utc>::from(modified).to_rfc3339()
+                    }),
+                });
+            }
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn index_response(
         &self,
         api_key: Result<String, HeaderError>,
@@ -776,28 +1906,125 @@ impl Service {
         domain: String,
         commit: String,
         count: usize,
+        model: Option<Model>,
+        allow_model_mismatch: bool,
+        dedup: bool,
+        explain: bool,
+        max_distance: Option<f32>,
+        preview: bool,
+        provenance: bool,
+        filter: Option<IdFilter>,
     ) -> Result<Response<Body>, ResponseError> {
+        let (_interactive, _) = InFlightGuard::new(&self.interactive_searches);
         let api_key = api_key?;
-        let vec: Vec<[f32; 1536]> = embeddings_for(&api_key, &[q]).await?;
+        let model = self.vector_store.get_domain(&domain)?.resolve_model(
+            model,
+            self.embedding_cache.provider(),
+            allow_model_mismatch,
+        )?;
+        let index_id = create_index_name(&domain, &commit);
+        let cache_key = QueryCacheKey {
+            index_id: index_id.clone(),
+            generation: self.index_generation.load(atomic::Ordering::Relaxed),
+            query_hash: {
+                let mut hasher = DefaultHasher::new();
+                q.hash(&mut hasher);
+                hasher.finish()
+            },
+            model,
+            count,
+            dedup,
+            max_distance: max_distance.map(f32::to_bits),
+            provenance,
+            filter: filter.clone(),
+        };
+        if !explain {
+            if let Some(body) = self.query_cache.get(&cache_key) {
+                return Ok(Response::builder().body(body.into()).unwrap());
+            }
+        }
+        let start = Instant::now();
+        let embeddings = self
+            .embedding_cache
+            .embeddings_for(&api_key, &[model.query_text(&q)], model)
+            .await?;
+        let embedding_seconds = start.elapsed().as_secs_f64();
         let qp = Point::Mem {
-            vec: Box::new(vec[0]),
+            vec: Box::new(embeddings.embeddings[0]),
         };
-        let index_id = create_index_name(&domain, &commit);
         // if None, then return 404
-        let hnsw = self.get_index(&index_id).await?;
-        let res = search(&qp, count, &hnsw).unwrap();
+        let (index, partial) = self.get_index_or_preview(&index_id, preview).await?;
+        let hnsw = &index.hnsw;
+        let start = Instant::now();
+        let resolved_domain = self.vector_store.get_domain(&domain)?;
+        resolved_domain.record_embedding_tokens(embeddings.usage.prompt_tokens);
+        let (res, statistics) = match &filter {
+            Some(filter) => {
+                search_filtered(&qp, count, hnsw, index.ids(), filter, &index.tombstones)
+            }
+            None => search_live_with_statistics(&qp, count, hnsw, &index.tombstones),
+        }
+        .unwrap();
+        let mut res = within_distance(res, max_distance);
+        let explanation = QueryExplanation {
+            search: statistics,
+            embedding_seconds,
+            search_seconds: start.elapsed().as_secs_f64(),
+        };
+        if let Some(threshold) = self.slow_query_threshold {
+            if explanation.embedding_seconds + explanation.search_seconds > threshold.as_secs_f64()
+            {
+                log!(
+                    "slow query on {index_id}: {q:?} {}",
+                    serde_json::to_string(&explanation)?
+                );
+            }
+        }
+        if dedup {
+            res = dedup_by_id(res);
+        }
+        let mut sources = if provenance {
+            let vec_ids: Vec<usize> = res.iter().map(|p| p.vec_id()).collect();
+            tokio::task::block_in_place(|| resolved_domain.provenance(&vec_ids))?
+        } else {
+            HashMap::new()
+        };
         let ids: Vec<QueryResult> = res
             .iter()
             .map(|p| QueryResult {
                 id: p.id().to_string(),
                 distance: f32::from_bits(p.distance()),
+                provenance: sources.remove(&p.vec_id()),
             })
             .collect();
-        let s = serde_json::to_string(&ids)?;
+        let s = if explain || partial {
+            let mut obj = json!({ "results": ids });
+            if explain {
+                obj["explain"] = serde_json::to_value(&explanation)?;
+            }
+            if partial {
+                obj["partial"] = true.into();
+                obj["indexed_documents"] = hnsw.layer_len(0).into();
+            }
+            serde_json::to_string(&obj)?
+        } else {
+            let s = serde_json::to_string(&ids)?;
+            self.query_cache.put(cache_key, s.clone());
+            s
+        };
         Ok(Response::builder().body(s.into()).unwrap())
     }
 }
 
+/// The response to a known resource requested with a method it
+/// doesn't support.
+fn method_not_allowed() -> Result<Response<Body>, Infallible> {
+    Ok(Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .body("Method not allowed".into())
+        .unwrap())
+}
+
 fn string_response_or_error(
     result: Result<String, ResponseError>,
 ) -> Result<Response<Body>, Infallible> {
@@ -835,12 +2062,22 @@ enum AssignIndexError {
     TargetCommitAlreadyHasIndex,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn serve<P: Into<PathBuf>>(
     directory: P,
     user_forward_header: String,
     port: u16,
     num_bufs: usize,
     content_endpoint: Option<String>,
+    embedding_cache: EmbeddingCache,
+    invalid_embedding_policy: InvalidEmbeddingPolicy,
+    slow_query_threshold: Option<Duration>,
+    file_access_key: Option<String>,
+    query_cache_size: usize,
+    query_cache_ttl: Duration,
+    preview_interval: Option<NonZeroUsize>,
+    max_in_flight: Option<usize>,
+    max_background_scans: NonZeroUsize,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
     let service = Arc::new(Service::new(
@@ -848,6 +2085,14 @@ pub async fn serve<P: Into<PathBuf>>(
         user_forward_header,
         num_bufs,
         content_endpoint,
+        embedding_cache,
+        invalid_embedding_policy,
+        slow_query_threshold,
+        file_access_key,
+        QueryCache::new(query_cache_size, query_cache_ttl),
+        preview_interval,
+        max_in_flight,
+        max_background_scans,
     ));
     let make_svc = make_service_fn(move |_conn| {
         let s = service.clone();
@@ -864,3 +2109,111 @@ pub async fn serve<P: Into<PathBuf>>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_has_fields(value: serde_json::Value, fields: &[&str]) {
+        let object = value.as_object().unwrap();
+        for field in fields {
+            assert!(object.contains_key(*field), "missing field {field}");
+        }
+    }
+
+    #[test]
+    fn files_belong_to_their_own_domain() {
+        assert_eq!(Some("foo"), file_domain("foo.vecs").as_deref());
+        assert_eq!(Some("foo.bar"), file_domain("foo.bar.vecs").as_deref());
+        assert_eq!(Some("foo"), file_domain("foo@abc.2.hnsw").as_deref());
+        assert_eq!(Some("foo.bar"), file_domain("foo.bar@abc.meta").as_deref());
+        assert_eq!(
+            Some("admin/foo"),
+            file_domain("admin%2Ffoo@abc.meta.tmp").as_deref()
+        );
+        assert_eq!(None, file_domain("README"));
+    }
+
+    #[test]
+    fn secrets_are_compared_in_full() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn versioned_paths_are_routed() {
+        let uri: Uri = "/v1/search?domain=admin/foo&commit=abc".parse().unwrap();
+        assert!(matches!(
+            uri_to_spec(&uri),
+            Ok(ResourceSpec::Search { count: 10, .. })
+        ));
+        let uri: Uri = "/v1/statistics".parse().unwrap();
+        assert!(matches!(uri_to_spec(&uri), Ok(ResourceSpec::GetStatistics)));
+        let uri: Uri = "/v1".parse().unwrap();
+        assert!(uri_to_spec(&uri).is_err());
+    }
+
+    #[test]
+    fn parse_search_id_filters() {
+        let uri: Uri = "/search?domain=foo&commit=abc&allow=%5B%22Doc%2F1%22%5D"
+            .parse()
+            .unwrap();
+        match uri_to_spec(&uri) {
+            Ok(ResourceSpec::Search {
+                filter: Some(IdFilter::Allow(ids)),
+                ..
+            }) => assert_eq!(vec!["Doc/1"], ids.into_iter().collect::<Vec<_>>()),
+            other => panic!("unexpected spec {other:?}"),
+        }
+        let uri: Uri = "/search?domain=foo&commit=abc&allow=%5B%5D&deny=%5B%5D"
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            uri_to_spec(&uri),
+            Err(SpecParseError::InvalidFilter(_))
+        ));
+    }
+
+    // The v1 response schemas. Fields may be added, but removing or
+    // renaming one breaks clients.
+    #[test]
+    fn v1_response_fields_are_stable() {
+        let result = QueryResult {
+            id: "Doc/1".to_string(),
+            distance: 0.5,
+            provenance: None,
+        };
+        assert_has_fields(serde_json::to_value(result).unwrap(), &["id", "distance"]);
+        assert_has_fields(
+            serde_json::to_value(IndexingSummary::default()).unwrap(),
+            &[
+                "indexed_documents",
+                "operations",
+                "rejected_embeddings",
+                "embedding_seconds",
+                "graph_build_seconds",
+                "serialization_seconds",
+                "serialized_size",
+                "recall_estimate",
+            ],
+        );
+        let explanation = QueryExplanation {
+            search: SearchStatistics::default(),
+            embedding_seconds: 0.0,
+            search_seconds: 0.0,
+        };
+        assert_has_fields(
+            serde_json::to_value(explanation).unwrap(),
+            &[
+                "distance_computations",
+                "index_layers",
+                "ef",
+                "embedding_seconds",
+                "search_seconds",
+            ],
+        );
+    }
+}
@@ -1,11 +1,25 @@
 #![allow(unused)]
 use rand::Rng;
+use thiserror::Error;
 
 pub const EMBEDDING_LENGTH: usize = 1536;
 pub const EMBEDDING_BYTE_LENGTH: usize = EMBEDDING_LENGTH * 4;
 pub type Embedding = [f32; EMBEDDING_LENGTH];
 pub type EmbeddingBytes = [u8; EMBEDDING_BYTE_LENGTH];
 
+pub const BINARY_EMBEDDING_LENGTH: usize = EMBEDDING_LENGTH / 64;
+pub const BINARY_EMBEDDING_BYTE_LENGTH: usize = BINARY_EMBEDDING_LENGTH * 8;
+pub type BinaryEmbedding = [u64; BINARY_EMBEDDING_LENGTH];
+
+/// Why an embedding cannot be meaningfully compared to others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Error)]
+pub enum EmbeddingProblem {
+    #[error("embedding contains NaN or infinite values")]
+    NonFinite,
+    #[error("embedding is the zero vector")]
+    Zero,
+}
+
 pub fn empty_embedding() -> Embedding {
     [0.0; EMBEDDING_LENGTH]
 }
@@ -17,6 +31,34 @@ pub fn random_embedding<R: Rng>(rng: &mut R) -> Embedding {
     embedding
 }
 
+/// Sample from the standard normal distribution using the Box-Muller
+/// transform.
+pub fn standard_normal<R: Rng>(rng: &mut R) -> f32 {
+    let u1: f32 = 1.0 - rng.gen::<f32>();
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+/// A normalized embedding with gaussian noise of standard deviation
+/// `spread` added to `center` in its first `dimension` components.
+pub fn random_embedding_near<R: Rng>(
+    rng: &mut R,
+    center: &Embedding,
+    spread: f32,
+    dimension: usize,
+) -> Embedding {
+    let mut embedding = *center;
+    for e in embedding.iter_mut().take(dimension) {
+        *e += spread * standard_normal(rng);
+    }
+    if embedding.iter().all(|f| *f == 0.0) {
+        embedding[0] = 1.0;
+    }
+    normalize_vec(&mut embedding);
+
+    embedding
+}
+
 pub fn random_normalized_embedding<R: Rng>(rng: &mut R) -> Embedding {
     let mut embedding = random_embedding(rng);
     normalize_vec(&mut embedding);
@@ -91,6 +133,85 @@ pub fn normalize_vec(vec: &mut Embedding) {
     normalize_vec_scalar(vec)
 }
 
+/// Sum embeddings scaled by their weight, normalizing the result.
+///
+/// Returns `None` if the weighted sum is the zero vector, which
+/// cannot be normalized.
+pub fn normalized_weighted_sum<'a, I: IntoIterator<Item = (&'a Embedding, f32)>>(
+    terms: I,
+) -> Option<Embedding> {
+    let mut result = empty_embedding();
+    for (embedding, weight) in terms {
+        for (r, e) in result.iter_mut().zip(embedding.iter()) {
+            *r += e * weight;
+        }
+    }
+    if result.iter().all(|f| *f == 0.0) {
+        return None;
+    }
+    normalize_vec(&mut result);
+
+    Some(result)
+}
+
+pub fn embedding_problem(embedding: &Embedding) -> Option<EmbeddingProblem> {
+    if embedding.iter().any(|f| !f.is_finite()) {
+        Some(EmbeddingProblem::NonFinite)
+    } else if embedding.iter().all(|f| *f == 0.0) {
+        Some(EmbeddingProblem::Zero)
+    } else {
+        None
+    }
+}
+
+/// Zero out non-finite values and renormalize. A vector left with
+/// nothing but zeroes becomes the normalized epsilon vector.
+pub fn repair_embedding(embedding: &mut Embedding) {
+    for f in embedding.iter_mut() {
+        if !f.is_finite() {
+            *f = 0.0;
+        }
+    }
+    if embedding.iter().all(|f| *f == 0.0) {
+        embedding.fill(f32::EPSILON);
+    }
+    normalize_vec(embedding);
+}
+
+/// Pairwise normalized cosine distances between `embeddings`.
+pub fn distance_matrix(embeddings: &[&Embedding]) -> Vec<Vec<f32>> {
+    let mut matrix = vec![vec![0.0; embeddings.len()]; embeddings.len()];
+    for i in 0..embeddings.len() {
+        for j in i + 1..embeddings.len() {
+            let distance = normalized_cosine_distance(embeddings[i], embeddings[j]);
+            matrix[i][j] = distance;
+            matrix[j][i] = distance;
+        }
+    }
+
+    matrix
+}
+
+/// Quantize an embedding to one bit per dimension, set when the
+/// dimension is positive.
+pub fn binarize(embedding: &Embedding) -> BinaryEmbedding {
+    let mut result = [0; BINARY_EMBEDDING_LENGTH];
+    for (i, f) in embedding.iter().enumerate() {
+        if *f > 0.0 {
+            result[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    result
+}
+
+pub fn hamming_distance(left: &BinaryEmbedding, right: &BinaryEmbedding) -> u32 {
+    left.iter()
+        .zip(right.iter())
+        .map(|(l, r)| (l ^ r).count_ones())
+        .sum()
+}
+
 #[cfg(feature = "simd")]
 pub mod simd {
     use super::*;
@@ -232,3 +353,59 @@ mod tests {
         assert_eq!(e1, e2);
     }
 }
+
+#[cfg(test)]
+mod binary_tests {
+    use super::*;
+
+    #[test]
+    fn hamming_distance_of_binarized_embeddings() {
+        let mut e1 = empty_embedding();
+        let mut e2 = empty_embedding();
+        e1[0] = 1.0;
+        e1[70] = 0.5;
+        e2[70] = 0.3;
+        e2[1535] = 0.1;
+        e2[3] = -1.0;
+
+        let b1 = binarize(&e1);
+        let b2 = binarize(&e2);
+        assert_eq!(1, b1[0]);
+        assert_eq!(1 << 6, b1[1]);
+        assert_eq!(0, hamming_distance(&b1, &b1));
+        assert_eq!(2, hamming_distance(&b1, &b2));
+    }
+
+    #[test]
+    fn repair_invalid_embeddings() {
+        let mut zero = empty_embedding();
+        assert_eq!(Some(EmbeddingProblem::Zero), embedding_problem(&zero));
+        repair_embedding(&mut zero);
+        assert_eq!(None, embedding_problem(&zero));
+        let norm: f32 = zero.iter().map(|f| f * f).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 0.001);
+
+        let mut nan = empty_embedding();
+        nan[0] = f32::NAN;
+        nan[1] = 2.0;
+        assert_eq!(Some(EmbeddingProblem::NonFinite), embedding_problem(&nan));
+        repair_embedding(&mut nan);
+        assert_eq!(0.0, nan[0]);
+        assert!((nan[1] - 1.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn distance_matrix_is_symmetric() {
+        let mut e1 = empty_embedding();
+        let mut e2 = empty_embedding();
+        let mut e3 = empty_embedding();
+        e1[0] = 1.0;
+        e2[1] = 1.0;
+        e3[0] = -1.0;
+
+        let matrix = distance_matrix(&[&e1, &e2, &e3]);
+        assert_eq!(vec![0.0, 0.5, 1.0], matrix[0]);
+        assert_eq!(matrix[0][1], matrix[1][0]);
+        assert_eq!(0.0, matrix[2][2]);
+    }
+}
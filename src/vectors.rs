@@ -3,7 +3,7 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
 use std::mem::MaybeUninit;
 use std::ops::Deref;
 use std::os::unix::prelude::FileExt;
@@ -12,10 +12,17 @@ use std::sync::atomic::{self, AtomicUsize};
 use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
 
 use lru::LruCache;
-use serde::Serialize;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use urlencoding::encode;
 
-use crate::vecmath::{Embedding, EmbeddingBytes, EMBEDDING_BYTE_LENGTH, EMBEDDING_LENGTH};
+use crate::log::log;
+use crate::openai::{EmbeddingProvider, Model};
+use crate::vecmath::{
+    binarize, empty_embedding, random_embedding_near, BinaryEmbedding, Embedding, EmbeddingBytes,
+    BINARY_EMBEDDING_BYTE_LENGTH, BINARY_EMBEDDING_LENGTH, EMBEDDING_BYTE_LENGTH, EMBEDDING_LENGTH,
+};
 
 // 3 memory pages of 4K hold 2 OpenAI vectors.
 // We set things up so that blocks are some multiple of 2 pages.
@@ -37,25 +44,113 @@ struct PinnedVectorPage {
     handle: Weak<PageHandle>,
 }
 
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+struct DomainManifest {
+    model: Option<Model>,
+    #[serde(default)]
+    embedder: Option<Embedder>,
+}
+
+/// What embedded the vectors of a domain: the provider, and the model
+/// by the name that provider knows it by.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Embedder {
+    pub provider: String,
+    pub model: String,
+}
+
+impl Embedder {
+    pub fn new(provider: &EmbeddingProvider, model: Model) -> Self {
+        Embedder {
+            provider: provider.name().to_string(),
+            model: provider.model_name(model),
+        }
+    }
+}
+
+impl fmt::Display for Embedder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} from {}", self.model, self.provider)
+    }
+}
+
+impl DomainManifest {
+    /// Read the manifest at `path`, which is empty if there is none.
+    fn read(path: &Path) -> io::Result<Self> {
+        match File::open(path) {
+            Ok(f) => serde_json::from_reader(f)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(DomainManifest::default()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Where a vector came from: the commit and line of the operation
+/// that produced it, and a hash of the embedded text.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    pub commit: String,
+    pub line: usize,
+    pub content_hash: u64,
+}
+
+// The provenance of a vector is a record at a fixed offset in the
+// provenance file of its domain: the number of its commit in the
+// commit table of the domain plus one, its line and its content hash,
+// in little endian. Vectors without provenance have a zero commit.
+const PROVENANCE_RECORD_LENGTH: usize = 4 + 8 + 8;
+
+#[derive(Debug, Error)]
+#[error("domain {domain} was embedded with {recorded}, but {requested} was requested")]
+pub struct ModelMismatchError {
+    pub domain: String,
+    pub recorded: Embedder,
+    pub requested: Embedder,
+}
+
 pub struct Domain {
     name: Arc<String>,
     index: usize,
     read_file: File,
     write_file: Mutex<File>,
     num_vecs: AtomicUsize,
+    manifest_path: PathBuf,
+    manifest: RwLock<DomainManifest>,
+    provenance_path: PathBuf,
+    commits_path: PathBuf,
+    // The commits that provenance records refer to, by number.
+    commits: Mutex<Vec<String>>,
+    reads: AtomicUsize,
+    bytes_read: AtomicUsize,
+    // Tokens charged by the embedding provider for this domain since
+    // it was opened.
+    embedding_tokens: AtomicUsize,
 }
 
 impl Domain {
     fn open(dir: &Path, name: &str, index: usize) -> io::Result<Self> {
         let mut path = dir.to_path_buf();
         let name = encode(name);
+        let mut manifest_path = path.clone();
+        manifest_path.push(format!("{name}.manifest"));
+        let manifest = DomainManifest::read(&manifest_path)?;
+        let mut provenance_path = path.clone();
+        provenance_path.push(format!("{name}.provenance"));
+        let mut commits_path = path.clone();
+        commits_path.push(format!("{name}.commits"));
+        let commits = match std::fs::read_to_string(&commits_path) {
+            Ok(commits) => commits.lines().map(str::to_string).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
         path.push(format!("{name}.vecs"));
         let mut write_file = File::options()
             .read(true)
             .write(true)
             .create(true)
             .truncate(false)
-            .open(dbg!(&path))?;
+            .open(&path)?;
         let pos = write_file.seek(SeekFrom::End(0))?;
         if pos as usize % EMBEDDING_BYTE_LENGTH != 0 {
             panic!("domain {name} has unexpected length");
@@ -75,9 +170,165 @@ impl Domain {
             read_file,
             write_file,
             num_vecs,
+            manifest_path,
+            manifest: RwLock::new(manifest),
+            provenance_path,
+            commits_path,
+            commits: Mutex::new(commits),
+            reads: AtomicUsize::new(0),
+            bytes_read: AtomicUsize::new(0),
+            embedding_tokens: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn model(&self) -> Option<Model> {
+        self.manifest.read().unwrap().model
+    }
+
+    /// What embedded the vectors of the domain. A manifest from before
+    /// providers were recorded belongs to a domain embedded by OpenAI.
+    pub fn embedder(&self) -> Option<Embedder> {
+        let manifest = self.manifest.read().unwrap();
+        manifest.embedder.clone().or_else(|| {
+            manifest
+                .model
+                .map(|model| Embedder::new(&EmbeddingProvider::OpenAI, model))
         })
     }
 
+    pub fn set_model(&self, model: Model, embedder: Embedder) -> io::Result<()> {
+        self.write_manifest(DomainManifest {
+            model: Some(model),
+            embedder: Some(embedder),
+        })
+    }
+
+    /// Record the same model as `source` has, for a domain with
+    /// vectors copied from it.
+    pub fn copy_model_from(&self, source: &Domain) -> io::Result<()> {
+        let manifest = source.manifest.read().unwrap().clone();
+        self.write_manifest(manifest)
+    }
+
+    /// Write the manifest to a temporary file and rename it into
+    /// place, so an interrupted write leaves the old manifest intact.
+    fn write_manifest(&self, new_manifest: DomainManifest) -> io::Result<()> {
+        let mut manifest = self.manifest.write().unwrap();
+        let mut temporary_path = self.manifest_path.clone().into_os_string();
+        temporary_path.push(".tmp");
+        let write_file = File::create(&temporary_path)?;
+        serde_json::to_writer(&write_file, &new_manifest)?;
+        write_file.sync_data()?;
+        std::fs::rename(&temporary_path, &self.manifest_path)?;
+        *manifest = new_manifest;
+        Ok(())
+    }
+
+    /// Count prompt tokens charged for embedding into this domain.
+    /// The count is kept in memory only, so it starts over whenever
+    /// the domain is opened.
+    pub fn record_embedding_tokens(&self, tokens: usize) {
+        self.embedding_tokens
+            .fetch_add(tokens, atomic::Ordering::Relaxed);
+    }
+
+    /// Record the provenance of vectors, given by their id, in the
+    /// domain's provenance file.
+    pub fn record_provenance(&self, records: Vec<(usize, Provenance)>) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&self.provenance_path)?;
+        let mut commits = self.commits.lock().unwrap();
+        for (vec, provenance) in records {
+            let commit = match commits.iter().rposition(|c| *c == provenance.commit) {
+                Some(commit) => commit,
+                None => {
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.commits_path)?
+                        .write_all(format!("{}\n", provenance.commit).as_bytes())?;
+                    commits.push(provenance.commit);
+                    commits.len() - 1
+                }
+            };
+            let mut record = [0; PROVENANCE_RECORD_LENGTH];
+            record[..4].copy_from_slice(&(commit as u32 + 1).to_le_bytes());
+            record[4..12].copy_from_slice(&(provenance.line as u64).to_le_bytes());
+            record[12..].copy_from_slice(&provenance.content_hash.to_le_bytes());
+            file.write_all_at(&record, (vec * PROVENANCE_RECORD_LENGTH) as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Look up the provenance of the given vectors. Vectors added
+    /// without provenance are left out.
+    pub fn provenance(&self, vecs: &[usize]) -> io::Result<HashMap<usize, Provenance>> {
+        let file = match File::open(&self.provenance_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+        let commits = self.commits.lock().unwrap();
+        let mut result = HashMap::new();
+        for &vec in vecs {
+            let mut record = [0; PROVENANCE_RECORD_LENGTH];
+            match file.read_exact_at(&mut record, (vec * PROVENANCE_RECORD_LENGTH) as u64) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => continue,
+                Err(e) => return Err(e),
+            }
+            let commit = u32::from_le_bytes(record[..4].try_into().unwrap()) as usize;
+            if commit == 0 {
+                continue;
+            }
+            let commit = commits.get(commit - 1).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("provenance of vector {vec} refers to an unknown commit"),
+                )
+            })?;
+            result.insert(
+                vec,
+                Provenance {
+                    commit: commit.clone(),
+                    line: u64::from_le_bytes(record[4..12].try_into().unwrap()) as usize,
+                    content_hash: u64::from_le_bytes(record[12..].try_into().unwrap()),
+                },
+            );
+        }
+        Ok(result)
+    }
+
+    /// Determine the model to use for this domain.
+    ///
+    /// If no model is requested, the model recorded for the domain
+    /// (or the default model) is used. A requested model that
+    /// differs from the recorded one is an error unless
+    /// `allow_mismatch` is set.
+    pub fn resolve_model(
+        &self,
+        requested: Option<Model>,
+        provider: &EmbeddingProvider,
+        allow_mismatch: bool,
+    ) -> Result<Model, ModelMismatchError> {
+        let model = requested.or(self.model()).unwrap_or_default();
+        let requested = Embedder::new(provider, model);
+        match self.embedder() {
+            Some(recorded) if recorded != requested && !allow_mismatch => Err(ModelMismatchError {
+                domain: self.name.to_string(),
+                recorded,
+                requested,
+            }),
+            _ => Ok(model),
+        }
+    }
+
     fn add_vecs<'a, I: Iterator<Item = &'a Embedding>>(
         &self,
         vecs: I,
@@ -103,9 +354,11 @@ impl Domain {
         let end = self.num_vecs() * std::mem::size_of::<Embedding>();
         if end <= offset {
             // this page does not exist.
-            eprintln!(
-                " :( page {} does not exist (need {} but end is {})",
-                index, offset, end
+            log!(
+                "page {} does not exist (need {} but end is {})",
+                index,
+                offset,
+                end
             );
             return Ok(false);
         }
@@ -115,13 +368,16 @@ impl Domain {
         } else {
             remainder
         };
-        eprintln!(
+        log!(
             "loading page {}, range at offset {} of len {}",
-            index, offset, data_len
+            index,
+            offset,
+            data_len
         );
         let data: &mut VectorPageBytes = unsafe { std::mem::transmute(data) };
         let data_slice = &mut data[..data_len];
         self.read_file.read_exact_at(data_slice, offset as u64)?;
+        self.record_read(data_len);
 
         Ok(true)
     }
@@ -132,15 +388,23 @@ impl Domain {
             "requested partial load would read past a page boundary"
         );
         let offset = index * std::mem::size_of::<VectorPage>() + offset;
-        eprintln!(
+        log!(
             "loading partial range at offset {} of len {}",
             offset,
             data.len()
         );
-        self.read_file.read_exact_at(data, offset as u64)
+        self.read_file.read_exact_at(data, offset as u64)?;
+        self.record_read(data.len());
+
+        Ok(())
+    }
+
+    fn record_read(&self, len: usize) {
+        self.reads.fetch_add(1, atomic::Ordering::Relaxed);
+        self.bytes_read.fetch_add(len, atomic::Ordering::Relaxed);
     }
 
-    fn num_vecs(&self) -> usize {
+    pub fn num_vecs(&self) -> usize {
         self.num_vecs.load(atomic::Ordering::Relaxed)
     }
 }
@@ -203,6 +467,10 @@ impl PageArena {
         free.push(zeroed);
     }
 
+    fn free_page_count(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
     fn free_page_from_free(&self) -> Option<Box<VectorPage>> {
         let mut free = self.free.lock().unwrap();
         free.pop()
@@ -399,6 +667,18 @@ impl PageArena {
             .or_else(|| self.page_from_loaded(spec))
     }
 
+    /// Number of pages of the given domain that are held in memory,
+    /// either in use or cached.
+    fn resident_pages(&self, domain: usize) -> usize {
+        let loaded = self.loaded.read().unwrap();
+        let cache = self.cache.read().unwrap();
+        loaded.keys().filter(|spec| spec.domain == domain).count()
+            + cache
+                .iter()
+                .filter(|(spec, _)| spec.domain == domain)
+                .count()
+    }
+
     pub fn statistics(&self) -> VectorStoreStatistics {
         let free = self.free.lock().unwrap().len();
         let loading = self.loading.lock().unwrap().len();
@@ -414,6 +694,60 @@ impl PageArena {
     }
 }
 
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct NormOutlier {
+    index: usize,
+    /// The id of the document the vector belongs to, when known.
+    id: Option<String>,
+    norm: f32,
+}
+
+/// Parameters for filling a domain with a synthetic mixture of
+/// gaussian clusters.
+#[derive(Debug, Clone)]
+pub struct SynthParameters {
+    pub size: usize,
+    pub clusters: usize,
+    pub dimension: usize,
+    pub spread: f32,
+    pub seed: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DomainStatistics {
+    vectors: usize,
+    zero_vectors: usize,
+    non_finite_vectors: usize,
+    min_norm: f32,
+    max_norm: f32,
+    norm_percentiles: Vec<(u8, f32)>,
+    dimension_means: Vec<f64>,
+    dimension_variances: Vec<f64>,
+    outliers: Vec<NormOutlier>,
+}
+
+impl DomainStatistics {
+    /// Name the documents the outliers belong to, as looked up by
+    /// their vector index.
+    pub fn identify_outliers(&mut self, id_of: impl Fn(usize) -> Option<String>) {
+        for outlier in &mut self.outliers {
+            outlier.id = id_of(outlier.index);
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DomainMetrics {
+    name: String,
+    vectors: usize,
+    file_size: usize,
+    reads: usize,
+    bytes_read: usize,
+    resident_pages: usize,
+    resident_bytes: usize,
+    embedding_tokens: usize,
+}
+
 #[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct VectorStoreStatistics {
     free: usize,
@@ -533,6 +867,13 @@ impl VectorStore {
         }
     }
 
+    /// The model of a domain, read without opening the domain, so that
+    /// no files are created for a domain that does not exist.
+    pub fn domain_model(&self, name: &str) -> io::Result<Option<Model>> {
+        let path = self.dir.join(format!("{}.manifest", encode(name)));
+        Ok(DomainManifest::read(&path)?.model)
+    }
+
     pub fn get_domain(&self, name: &str) -> io::Result<Arc<Domain>> {
         let domains = self.domains.read().unwrap();
         if let Some(domain) = domains.get(name) {
@@ -605,7 +946,6 @@ impl VectorStore {
             // the page is on disk but not yet in memory. Let's load it.
             match self.arena.start_loading_or_wait(page_spec) {
                 LoadState::Loading => {
-                    eprintln!(" loading page");
                     // we are the loader. get a free page and load things
                     if let Some(mut page) = self.arena.free_page() {
                         match domain.load_page(page_index, &mut page) {
@@ -684,9 +1024,230 @@ impl VectorStore {
         Ok(result)
     }
 
+    /// Grow the arena so that the pages of the entire domain, plus
+    /// `additional` vectors that are yet to be added, can be resident
+    /// at once. Pages of the domain that already are resident count
+    /// towards this.
+    ///
+    /// This only makes room for the pages; they are still loaded on
+    /// demand. It avoids evicting and reloading pages while building
+    /// an index for a small domain.
+    pub fn reserve_for_domain(&self, domain: &Domain, additional: usize) {
+        let num_vecs = domain.num_vecs() + additional;
+        let pages_needed = num_vecs.div_ceil(VECTORS_PER_PAGE);
+        let resident = self.arena.resident_pages(domain.index);
+        let free = self.arena.free_page_count();
+        self.arena
+            .alloc_free_pages(pages_needed.saturating_sub(resident + free));
+    }
+
+    /// Write a binary quantized copy of the domain to a `.bits` file
+    /// next to its `.vecs` file, returning the number of vectors
+    /// written.
+    pub fn binarize_domain(&self, domain: &Domain) -> io::Result<usize> {
+        let mut path = self.dir.clone();
+        path.push(format!("{}.bits", domain.name));
+        let mut write_file = BufWriter::new(File::create(path)?);
+        let num_vecs = domain.num_vecs();
+        for index in 0..num_vecs {
+            let vec = self.get_vec(domain, index)?.unwrap();
+            for word in binarize(&vec) {
+                write_file.write_all(&word.to_le_bytes())?;
+            }
+        }
+        write_file.flush()?;
+        write_file.get_ref().sync_data()?;
+
+        Ok(num_vecs)
+    }
+
+    /// Read back the binary quantized copy of the domain written by
+    /// [`VectorStore::binarize_domain`].
+    pub fn load_binarized_domain(&self, domain: &Domain) -> io::Result<Vec<BinaryEmbedding>> {
+        let mut path = self.dir.clone();
+        path.push(format!("{}.bits", domain.name));
+        let bytes = std::fs::read(path)?;
+        if bytes.len() % BINARY_EMBEDDING_BYTE_LENGTH != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "binarized domain has a partial vector at the end",
+            ));
+        }
+        Ok(bytes
+            .chunks_exact(BINARY_EMBEDDING_BYTE_LENGTH)
+            .map(|chunk| {
+                let mut result = [0; BINARY_EMBEDDING_LENGTH];
+                for (word, bytes) in result.iter_mut().zip(chunk.chunks_exact(8)) {
+                    *word = u64::from_le_bytes(bytes.try_into().unwrap());
+                }
+                result
+            })
+            .collect())
+    }
+
+    /// Fill an empty domain with vectors drawn around randomly placed
+    /// cluster centers, writing the cluster of every vector as a little
+    /// endian u32 to a `.labels` file next to its `.vecs` file.
+    pub fn synthesize_domain(
+        &self,
+        domain: &Domain,
+        parameters: &SynthParameters,
+    ) -> io::Result<()> {
+        if domain.num_vecs() != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "can only synthesize into an empty domain",
+            ));
+        }
+        let mut rng = StdRng::seed_from_u64(parameters.seed);
+        let dimension = parameters.dimension.clamp(1, EMBEDDING_LENGTH);
+        let origin = empty_embedding();
+        let centers: Vec<Embedding> = (0..parameters.clusters.max(1))
+            .map(|_| random_embedding_near(&mut rng, &origin, 1.0, dimension))
+            .collect();
+
+        let mut path = self.dir.clone();
+        path.push(format!("{}.labels", domain.name));
+        let mut labels_file = BufWriter::new(File::create(path)?);
+        let mut remaining = parameters.size;
+        while remaining != 0 {
+            let batch_size = remaining.min(VECTORS_PER_PAGE * 64);
+            let mut batch = Vec::with_capacity(batch_size);
+            for _ in 0..batch_size {
+                let label = rng.gen_range(0..centers.len());
+                batch.push(random_embedding_near(
+                    &mut rng,
+                    &centers[label],
+                    parameters.spread,
+                    dimension,
+                ));
+                labels_file.write_all(&(label as u32).to_le_bytes())?;
+            }
+            self.add_vecs(domain, batch.iter())?;
+            remaining -= batch_size;
+        }
+        labels_file.flush()?;
+        labels_file.get_ref().sync_data()?;
+
+        Ok(())
+    }
+
+    /// Stream through all vectors of a domain, gathering statistics
+    /// about their norms and dimensions.
+    ///
+    /// Vectors with NaN or infinite values are counted but otherwise
+    /// left out of the statistics. The `outlier_count` vectors whose
+    /// norm is furthest from the median norm are reported as outliers.
+    pub fn domain_statistics(
+        &self,
+        domain: &Domain,
+        outlier_count: usize,
+    ) -> io::Result<DomainStatistics> {
+        let num_vecs = domain.num_vecs();
+        let mut zero_vectors = 0;
+        let mut non_finite_vectors = 0;
+        let mut norms: Vec<(usize, f32)> = Vec::with_capacity(num_vecs);
+        let mut means = vec![0.0_f64; EMBEDDING_LENGTH];
+        let mut m2 = vec![0.0_f64; EMBEDDING_LENGTH];
+        for index in 0..num_vecs {
+            let vec = self.get_vec(domain, index)?.unwrap();
+            if vec.iter().any(|f| !f.is_finite()) {
+                non_finite_vectors += 1;
+                continue;
+            }
+            if vec.iter().all(|f| *f == 0.0) {
+                zero_vectors += 1;
+            }
+            norms.push((index, vec.iter().map(|f| f * f).sum::<f32>().sqrt()));
+            // Welford's online algorithm for mean and variance
+            let n = norms.len() as f64;
+            for ((f, mean), m2) in vec.iter().zip(means.iter_mut()).zip(m2.iter_mut()) {
+                let f = *f as f64;
+                let delta = f - *mean;
+                *mean += delta / n;
+                *m2 += delta * (f - *mean);
+            }
+        }
+        let counted = norms.len();
+        let variances = m2
+            .into_iter()
+            .map(|m2| {
+                if counted > 1 {
+                    m2 / counted as f64
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        norms.sort_by(|(_, n1), (_, n2)| n1.total_cmp(n2));
+        let percentile = |p: usize| norms[(counted - 1) * p / 100].1;
+        let (min_norm, max_norm, norm_percentiles) = if counted == 0 {
+            (0.0, 0.0, Vec::new())
+        } else {
+            let percentiles = [1, 5, 25, 50, 75, 95, 99]
+                .into_iter()
+                .map(|p| (p as u8, percentile(p)))
+                .collect();
+            (norms[0].1, norms[counted - 1].1, percentiles)
+        };
+        let outliers = if counted == 0 {
+            Vec::new()
+        } else {
+            let median = percentile(50);
+            let mut by_deviation = norms;
+            by_deviation
+                .sort_by(|(_, n1), (_, n2)| (n2 - median).abs().total_cmp(&(n1 - median).abs()));
+            by_deviation
+                .into_iter()
+                .take(outlier_count)
+                .map(|(index, norm)| NormOutlier {
+                    index,
+                    id: None,
+                    norm,
+                })
+                .collect()
+        };
+
+        Ok(DomainStatistics {
+            vectors: num_vecs,
+            zero_vectors,
+            non_finite_vectors,
+            min_norm,
+            max_norm,
+            norm_percentiles,
+            dimension_means: means,
+            dimension_variances: variances,
+            outliers,
+        })
+    }
+
     pub fn statistics(&self) -> VectorStoreStatistics {
         self.arena.statistics()
     }
+
+    /// Size, read activity and memory residency of every open domain.
+    pub fn domain_metrics(&self) -> Vec<DomainMetrics> {
+        let domains = self.domains.read().unwrap();
+        let mut metrics: Vec<DomainMetrics> = domains
+            .iter()
+            .map(|(name, domain)| {
+                let resident_pages = self.arena.resident_pages(domain.index);
+                DomainMetrics {
+                    name: name.clone(),
+                    vectors: domain.num_vecs(),
+                    file_size: domain.num_vecs() * EMBEDDING_BYTE_LENGTH,
+                    reads: domain.reads.load(atomic::Ordering::Relaxed),
+                    bytes_read: domain.bytes_read.load(atomic::Ordering::Relaxed),
+                    resident_pages,
+                    resident_bytes: resident_pages * VECTOR_PAGE_BYTE_SIZE,
+                    embedding_tokens: domain.embedding_tokens.load(atomic::Ordering::Relaxed),
+                }
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.name.cmp(&b.name));
+        metrics
+    }
 }
 
 #[cfg(test)]
@@ -870,6 +1431,91 @@ mod tests {
         assert_eq!(e3, *e3_from_mem);
     }
 
+    #[test]
+    fn reserve_for_domain_allocates_missing_pages() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 1);
+        let domain = store.get_domain("foo").unwrap();
+
+        store.reserve_for_domain(&domain, VECTORS_PER_PAGE * 3 + 1);
+        assert_eq!(
+            VectorStoreStatistics {
+                free: 4,
+                loading: 0,
+                loaded: 0,
+                cached: 0
+            },
+            store.statistics()
+        );
+
+        store.reserve_for_domain(&domain, 1);
+        assert_eq!(4, store.statistics().free);
+
+        // The page this vector is on is already resident.
+        let vec = store
+            .add_and_load_vec(&domain, &[0.0; EMBEDDING_LENGTH])
+            .unwrap();
+        assert_eq!(3, store.statistics().free);
+        store.reserve_for_domain(&domain, VECTORS_PER_PAGE * 4 - 1);
+        assert_eq!(3, store.statistics().free);
+    }
+
+    #[test]
+    fn domain_model_does_not_create_the_domain() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 1);
+        assert_eq!(None, store.domain_model("foo").unwrap());
+        assert_eq!(0, std::fs::read_dir(path).unwrap().count());
+
+        let domain = store.get_domain("foo").unwrap();
+        domain
+            .set_model(
+                Model::SMALL3,
+                Embedder::new(&EmbeddingProvider::OpenAI, Model::SMALL3),
+            )
+            .unwrap();
+        assert_eq!(Some(Model::SMALL3), store.domain_model("foo").unwrap());
+    }
+
+    #[test]
+    fn domain_statistics_finds_zero_and_nan_vectors() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 100);
+        let domain = store.get_domain("foo").unwrap();
+
+        let mut e1 = [0.0; EMBEDDING_LENGTH];
+        e1[0] = 1.0;
+        let mut e2 = [0.0; EMBEDDING_LENGTH];
+        e2[0] = 3.0;
+        let e3 = [0.0; EMBEDDING_LENGTH];
+        let mut e4 = [0.0; EMBEDDING_LENGTH];
+        e4[1] = f32::NAN;
+        store.add_vecs(&domain, [e1, e2, e3, e4].iter()).unwrap();
+
+        let statistics = store.domain_statistics(&domain, 1).unwrap();
+        assert_eq!(4, statistics.vectors);
+        assert_eq!(1, statistics.zero_vectors);
+        assert_eq!(1, statistics.non_finite_vectors);
+        assert_eq!(0.0, statistics.min_norm);
+        assert_eq!(3.0, statistics.max_norm);
+        assert!((statistics.dimension_means[0] - 4.0 / 3.0).abs() < 1e-9);
+        assert_eq!(
+            vec![NormOutlier {
+                index: 1,
+                id: None,
+                norm: 3.0
+            }],
+            statistics.outliers
+        );
+
+        let mut statistics = statistics;
+        statistics.identify_outliers(|index| Some(format!("doc{index}")));
+        assert_eq!(Some("doc1"), statistics.outliers[0].id.as_deref());
+    }
+
     #[test]
     fn add_and_load_single() {
         let tempdir = tempfile::tempdir().unwrap();
@@ -910,6 +1556,48 @@ mod tests {
         assert_eq!(e5, *result[4]);
     }
 
+    #[test]
+    fn domain_model_is_recorded() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 100);
+        let domain = store.get_domain("foo").unwrap();
+        let openai = EmbeddingProvider::OpenAI;
+        assert_eq!(None, domain.model());
+        assert_eq!(
+            Model::ADA2,
+            domain.resolve_model(None, &openai, false).unwrap()
+        );
+
+        domain
+            .set_model(Model::SMALL3, Embedder::new(&openai, Model::SMALL3))
+            .unwrap();
+        assert_eq!(
+            Model::SMALL3,
+            domain.resolve_model(None, &openai, false).unwrap()
+        );
+        assert!(domain
+            .resolve_model(Some(Model::ADA2), &openai, false)
+            .is_err());
+        assert_eq!(
+            Model::ADA2,
+            domain
+                .resolve_model(Some(Model::ADA2), &openai, true)
+                .unwrap()
+        );
+        // The same model name from another provider is another model.
+        let local = EmbeddingProvider::local("http://localhost:8081").unwrap();
+        assert!(domain.resolve_model(None, &local, false).is_err());
+
+        let store2 = VectorStore::new(path, 100);
+        let domain2 = store2.get_domain("foo").unwrap();
+        assert_eq!(Some(Model::SMALL3), domain2.model());
+        assert_eq!(
+            Some(Embedder::new(&openai, Model::SMALL3)),
+            domain2.embedder()
+        );
+    }
+
     #[test]
     fn add_and_load_array() {
         let tempdir = tempfile::tempdir().unwrap();
@@ -935,4 +1623,77 @@ mod tests {
         assert_eq!(e4, *e4_from_memory);
         assert_eq!(e5, *e5_from_memory);
     }
+
+    #[test]
+    fn synthesize_clustered_domain() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+        let domain = store.get_domain("synth").unwrap();
+        let parameters = SynthParameters {
+            size: 10,
+            clusters: 3,
+            dimension: 16,
+            spread: 0.01,
+            seed: 42,
+        };
+        store.synthesize_domain(&domain, &parameters).unwrap();
+
+        assert_eq!(10, domain.num_vecs());
+        let labels = std::fs::read(path.join("synth.labels")).unwrap();
+        assert_eq!(40, labels.len());
+        let vec = store.get_vec(&domain, 3).unwrap().unwrap();
+        assert!(vec[16..].iter().all(|f| *f == 0.0));
+
+        assert!(store.synthesize_domain(&domain, &parameters).is_err());
+    }
+
+    #[test]
+    fn record_and_look_up_provenance() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        assert!(domain.provenance(&[0]).unwrap().is_empty());
+        let provenance = |commit: &str, line| Provenance {
+            commit: commit.to_string(),
+            line,
+            content_hash: 42,
+        };
+        domain
+            .record_provenance(vec![(0, provenance("abc", 1)), (1, provenance("abc", 2))])
+            .unwrap();
+        domain
+            .record_provenance(vec![(3, provenance("def", 5))])
+            .unwrap();
+        let found = domain.provenance(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(2, found.len());
+        assert_eq!(provenance("abc", 2), found[&1]);
+        assert_eq!(provenance("def", 5), found[&3]);
+
+        let reopened = Domain::open(tempdir.path(), "foo", 0).unwrap();
+        assert_eq!(found, reopened.provenance(&[1, 2, 3, 4]).unwrap());
+    }
+
+    #[test]
+    fn domain_metrics_count_reads_and_resident_pages() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+        let domain = store.get_domain("foo").unwrap();
+        let e = [1.0; 1536];
+        store.add_vecs(&domain, [e, e, e].iter()).unwrap();
+
+        let metrics = store.domain_metrics();
+        assert_eq!(1, metrics.len());
+        assert_eq!(3, metrics[0].vectors);
+        assert_eq!(3 * EMBEDDING_BYTE_LENGTH, metrics[0].file_size);
+        assert_eq!(0, metrics[0].reads);
+
+        let vec = store.get_vec(&domain, 2).unwrap().unwrap();
+        let metrics = store.domain_metrics();
+        assert_eq!(1, metrics[0].reads);
+        // The third vector is alone on the second page.
+        assert_eq!(EMBEDDING_BYTE_LENGTH, metrics[0].bytes_read);
+        assert_eq!(1, metrics[0].resident_pages);
+    }
 }